@@ -0,0 +1,154 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+/// The contents of the configuration file.
+///
+/// ```toml
+/// [sets.payments-prod]
+/// groups = ["/aws/lambda/payments-api", "/aws/lambda/payments-worker"]
+/// region = "eu-west-1"
+/// profile = "payments"
+/// refresh = "5s"
+/// filter = "?ERROR ?Exception"
+///
+/// [sets.payments-prod.colors]
+/// group = "cyan.bold"
+///
+/// [[rules]]
+/// pattern = "panicked at"
+/// level = "fatal"
+///
+/// [accounts]
+/// 123456789012 = "payments-prod"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Named sets of log groups that can be watched with `--set`.
+    #[serde(default)]
+    pub sets: HashMap<String, WatchSet>,
+    /// Aliases to show for AWS account IDs.
+    #[serde(default)]
+    pub accounts: HashMap<String, String>,
+    /// Level detection and highlighting rules that apply to every watch.
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+}
+
+/// A named set of log groups, along with the options used to watch them.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WatchSet {
+    #[serde(default)]
+    pub groups: Vec<String>,
+    pub region: Option<String>,
+    pub profile: Option<String>,
+    pub role_arn: Option<String>,
+    pub refresh: Option<String>,
+    pub filter: Option<String>,
+    #[serde(default)]
+    pub colors: ColorConfig,
+    /// Level detection and highlighting rules that apply to this set, ahead of the global rules.
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+}
+
+/// Overrides for the styles used in text output, given in the dotted form understood by
+/// [`console::Style::from_dotted_str`] (such as `red` or `yellow.bold`).
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ColorConfig {
+    pub timestamp: Option<String>,
+    pub group: Option<String>,
+    pub stream: Option<String>,
+    pub trace: Option<String>,
+    pub debug: Option<String>,
+    pub info: Option<String>,
+    pub warn: Option<String>,
+    pub error: Option<String>,
+    pub fatal: Option<String>,
+}
+
+/// A rule that styles messages matching a regular expression, and optionally sets their level.
+///
+/// ```toml
+/// [[rules]]
+/// pattern = "req-[0-9a-f]+"
+/// style = "cyan"
+/// span = true
+/// ```
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    pub pattern: String,
+    /// The style to apply, which defaults to the style of the rule's level.
+    pub style: Option<String>,
+    /// The level of messages that match, such as `warn` or `error`.
+    pub level: Option<String>,
+    /// Rules with a higher priority are tried first (default: 100, ahead of the built-in levels).
+    pub priority: Option<i32>,
+    /// Style only the matched text rather than the whole message.
+    #[serde(default)]
+    pub span: bool,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "could not read {}: {}", path.display(), err),
+            ConfigError::Parse(path, err) => {
+                write!(f, "could not parse {}: {}", path.display(), err)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Get the path to the default configuration file, `$XDG_CONFIG_HOME/cloudwatcher/config.toml`,
+/// falling back to `~/.config/cloudwatcher/config.toml`.
+pub fn default_config_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|dir| dir.join("cloudwatcher").join("config.toml"))
+}
+
+impl Config {
+    /// Load the configuration file.
+    ///
+    /// If a path is given then that file must exist. Otherwise we try the default path, and use
+    /// an empty configuration if there is no file there.
+    pub fn load(path: Option<&Path>) -> Result<Config, ConfigError> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_config_path() {
+                Some(path) => (path, false),
+                None => return Ok(Config::default()),
+            },
+        };
+
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if !required && err.kind() == io::ErrorKind::NotFound => {
+                return Ok(Config::default())
+            }
+            Err(err) => return Err(ConfigError::Io(path, err)),
+        };
+
+        toml::from_str(&content).map_err(|err| ConfigError::Parse(path, err))
+    }
+}
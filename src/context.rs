@@ -0,0 +1,87 @@
+use aws_config::{default_provider::credentials::DefaultCredentialsChain, sts::AssumeRoleProvider};
+use aws_sdk_cloudwatchlogs::{Endpoint, Region};
+use aws_types::{credentials::SharedCredentialsProvider, SdkConfig};
+use http::Uri;
+
+/// The credentials and region with which we talk to AWS.
+///
+/// Each distinct context gets its own client, so that groups in different accounts and regions can
+/// be watched side by side.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ClientContext {
+    /// The named profile to take credentials from, rather than the default credentials chain.
+    pub profile: Option<String>,
+    /// The ARN of a role to assume, using the credentials from the profile (or default chain).
+    pub role_arn: Option<String>,
+    pub region: String,
+    /// A custom endpoint to use instead of AWS, such as LocalStack.
+    pub endpoint_url: Option<Uri>,
+}
+
+impl ClientContext {
+    /// Apply a group qualifier to this context. Qualifiers that look like an ARN are taken to be
+    /// the role to assume, and anything else is taken to be a profile name.
+    pub fn qualified(&self, qualifier: &str) -> ClientContext {
+        let mut context = self.clone();
+        if qualifier.starts_with("arn:") {
+            context.role_arn = Some(qualifier.to_string());
+        } else {
+            context.profile = Some(qualifier.to_string());
+            context.role_arn = None;
+        }
+
+        context
+    }
+
+    /// Load the AWS configuration for this context.
+    pub async fn load(&self) -> SdkConfig {
+        let region = Region::new(self.region.clone());
+        let mut credentials = DefaultCredentialsChain::builder().region(region.clone());
+        if let Some(profile) = &self.profile {
+            credentials = credentials.profile_name(profile);
+        }
+
+        let credentials = SharedCredentialsProvider::new(credentials.build().await);
+        let credentials = match &self.role_arn {
+            Some(role_arn) => SharedCredentialsProvider::new(
+                AssumeRoleProvider::builder(role_arn)
+                    .session_name("cloudwatcher")
+                    .region(region.clone())
+                    .build(credentials),
+            ),
+            None => credentials,
+        };
+
+        let mut loader = aws_config::from_env()
+            .region(region)
+            .credentials_provider(credentials);
+        if let Some(endpoint_url) = &self.endpoint_url {
+            loader = loader.endpoint_resolver(Endpoint::immutable(endpoint_url.clone()));
+        }
+
+        loader.load().await
+    }
+
+    /// A name for this context's credentials: the role, the profile, or `default`.
+    pub fn credentials_name(&self) -> &str {
+        self.role_arn
+            .as_deref()
+            .or(self.profile.as_deref())
+            .unwrap_or("default")
+    }
+}
+
+/// Find the ID of the account that the configuration's credentials belong to.
+pub async fn account_id(config: &SdkConfig) -> Option<String> {
+    match aws_sdk_sts::Client::new(config)
+        .get_caller_identity()
+        .send()
+        .await
+    {
+        Ok(identity) => identity.account,
+        Err(err) => {
+            log::warn!("Unable to determine AWS account: {}", err);
+            None
+        }
+    }
+}
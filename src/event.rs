@@ -0,0 +1,50 @@
+use time::OffsetDateTime;
+
+/// A log event retrieved from one of the groups we're watching.
+#[derive(Debug, Clone)]
+pub struct LogEvent {
+    pub event_id: String,
+    /// The label of the account the event came from, when watching more than one.
+    pub account: Option<String>,
+    pub region: String,
+    pub group: String,
+    pub stream: String,
+    pub timestamp: OffsetDateTime,
+    pub ingestion_time: OffsetDateTime,
+    pub message: String,
+}
+
+impl LogEvent {
+    /// A label for the event's group, the same as its [`WatchGroup`](crate::watch::WatchGroup) is
+    /// labelled with.
+    pub fn group_label(&self, show_region: bool) -> String {
+        group_label(
+            self.account.as_deref(),
+            &self.region,
+            &self.group,
+            show_region,
+        )
+    }
+}
+
+/// Label a log group as `[ACCOUNT@][REGION:]GROUP`, where the account is only given when watching
+/// more than one, and the region when `show_region` is set.
+pub fn group_label(account: Option<&str>, region: &str, group: &str, show_region: bool) -> String {
+    match (account, show_region) {
+        (Some(account), true) => format!("{}@{}:{}", account, region, group),
+        (Some(account), false) => format!("{}@{}", account, group),
+        (None, true) => format!("{}:{}", region, group),
+        (None, false) => group.to_string(),
+    }
+}
+
+/// Get the number of milliseconds since the Unix epoch for the given time.
+pub fn unix_millis(time: OffsetDateTime) -> i64 {
+    (time.unix_timestamp_nanos() / 1_000_000) as i64
+}
+
+/// Get the time from a number of milliseconds since the Unix epoch.
+pub fn from_unix_millis(millis: i64) -> OffsetDateTime {
+    OffsetDateTime::from_unix_timestamp_nanos(millis as i128 * 1_000_000)
+        .expect("Failed to parse timestamp")
+}
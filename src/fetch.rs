@@ -0,0 +1,468 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+};
+
+use futures::future::join_all;
+
+use crate::{
+    context::ClientContext,
+    event::LogEvent,
+    source::{LogSource, SourceError},
+    watch::{get_group_events, PollBudget, WatchGroup},
+};
+
+/// How far back we first look for a group's most recent events. The window doubles each time it
+/// comes up short, and is narrowed when it holds too many events.
+const RECENT_EVENTS_FIRST_WINDOW: i64 = 10_000;
+
+/// How far back we look for a quiet group's most recent events before giving up (a year).
+const RECENT_EVENTS_MAX_LOOKBACK: i64 = 365 * 24 * 60 * 60 * 1000;
+
+/// The most events we fetch from a single window while looking for a group's most recent events.
+const RECENT_EVENTS_WINDOW_EVENTS: usize = 10_000;
+
+/// The most requests we make while looking for a group's most recent events, so that a filter that
+/// rarely matches can't have us read through a year of a busy group.
+const RECENT_EVENTS_MAX_REQUESTS: usize = 100;
+
+/// Pages are fetched one at a time while merging groups, so that only a page of each is held.
+const ONE_PAGE: PollBudget = PollBudget {
+    max_pages: 1,
+    max_events: usize::MAX,
+};
+
+/// A group's events that have been fetched but not yet merged with the other groups.
+struct GroupPages<'a> {
+    group: &'a WatchGroup,
+    events: VecDeque<LogEvent>,
+    next_token: Option<String>,
+    /// Set once the last page has been fetched, or fetching failed.
+    finished: bool,
+}
+
+impl<'a> GroupPages<'a> {
+    fn new(group: &'a WatchGroup) -> Self {
+        Self {
+            group,
+            events: VecDeque::new(),
+            next_token: None,
+            finished: false,
+        }
+    }
+
+    /// Whether we need this group's next page before we know which event comes next.
+    fn is_waiting(&self) -> bool {
+        self.events.is_empty() && !self.finished
+    }
+
+    /// Fetch the group's next page of events between `start_time` and `end_time`.
+    async fn fetch_page<S: LogSource + ?Sized>(
+        &mut self,
+        source: &S,
+        start_time: i64,
+        end_time: i64,
+    ) -> Result<(), SourceError> {
+        let result = get_group_events(
+            source,
+            self.group,
+            start_time,
+            Some(end_time),
+            self.next_token.take(),
+            ONE_PAGE,
+        )
+        .await;
+
+        match result {
+            Ok(page) => {
+                self.next_token = page.next_token().map(str::to_string);
+                self.finished = self.next_token.is_none();
+                self.events.extend(page.events);
+                Ok(())
+            }
+            Err(err) => {
+                self.finished = true;
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Fetch every event between `start_time` and `end_time` (in milliseconds, inclusive) from each
+/// group, passing them to `output` in timestamp order as they arrive.
+///
+/// Each group's pages are already in timestamp order, so only a page of each group is held at a
+/// time. A group that fails doesn't stop the others; the errors are returned once every group has
+/// been fetched.
+pub async fn fetch_events<'a, S: LogSource>(
+    sources: &HashMap<ClientContext, S>,
+    groups: &'a [WatchGroup],
+    start_time: i64,
+    end_time: i64,
+    mut output: impl FnMut(LogEvent),
+) -> Vec<(&'a WatchGroup, SourceError)> {
+    let mut pages: Vec<_> = groups.iter().map(GroupPages::new).collect();
+    let mut errors = Vec::new();
+
+    loop {
+        let waiting: Vec<_> = pages
+            .iter_mut()
+            .filter(|pages| pages.is_waiting())
+            .collect();
+        if !waiting.is_empty() {
+            let results = join_all(waiting.into_iter().map(|pages| async move {
+                let source = &sources[&pages.group.context];
+                (
+                    pages.group,
+                    pages.fetch_page(source, start_time, end_time).await,
+                )
+            }))
+            .await;
+            for (group, result) in results {
+                if let Err(err) = result {
+                    errors.push((group, err));
+                }
+            }
+
+            // Pages can be empty, so check again
+            continue;
+        }
+
+        // Events with the same timestamp are taken in the order of their groups
+        let next = pages
+            .iter_mut()
+            .filter(|pages| !pages.events.is_empty())
+            .min_by_key(|pages| pages.events[0].timestamp);
+        match next.and_then(|pages| pages.events.pop_front()) {
+            Some(event) => output(event),
+            None => return errors,
+        }
+    }
+}
+
+/// Fetch the `count` most recent events up to `end_time` (in milliseconds, inclusive) across the
+/// groups that pass the filter, in timestamp order.
+///
+/// Quiet groups are searched further and further back until enough of their events are found.
+/// Events that don't pass the filter aren't counted, so filters that can only be applied here
+/// still leave `count` events to show.
+pub async fn recent_events<'a, S, F>(
+    sources: &HashMap<ClientContext, S>,
+    groups: &'a [WatchGroup],
+    count: usize,
+    end_time: i64,
+    filter: F,
+) -> (Vec<LogEvent>, Vec<(&'a WatchGroup, SourceError)>)
+where
+    S: LogSource,
+    F: Fn(&LogEvent) -> bool,
+{
+    let filter = &filter;
+    let (mut events, errors) = fetch_groups(sources, groups, |source, group| async move {
+        group_recent_events(source, group, count, end_time, filter).await
+    })
+    .await;
+
+    let events = events.split_off(events.len().saturating_sub(count));
+    (events, errors)
+}
+
+/// Fetch the `count` most recent events from the group that pass the filter, in timestamp order.
+///
+/// Fewer events are returned if the search back runs out of requests first.
+async fn group_recent_events<S: LogSource + ?Sized>(
+    source: &S,
+    group: &WatchGroup,
+    count: usize,
+    end_time: i64,
+    filter: impl Fn(&LogEvent) -> bool,
+) -> Result<Vec<LogEvent>, SourceError> {
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+
+    let earliest = end_time - RECENT_EVENTS_MAX_LOOKBACK;
+    let mut events = Vec::new();
+    let mut window_end = end_time;
+    let mut window = RECENT_EVENTS_FIRST_WINDOW;
+    let mut requests = 0;
+
+    while requests < RECENT_EVENTS_MAX_REQUESTS {
+        let window_start = (window_end - window).max(earliest);
+        let older = get_group_events(
+            source,
+            group,
+            window_start,
+            Some(window_end),
+            None,
+            PollBudget {
+                max_pages: RECENT_EVENTS_MAX_REQUESTS - requests,
+                max_events: RECENT_EVENTS_WINDOW_EVENTS,
+            },
+        )
+        .await?;
+        requests += older.pages;
+
+        // We only have the start of a window that's too busy, so look at less of it
+        if !older.is_drained() {
+            if window <= 1 {
+                break;
+            }
+            window /= 4;
+            continue;
+        }
+
+        let mut older = older.events;
+        older.retain(|event| filter(event));
+        older.append(&mut events);
+        events = older.split_off(older.len().saturating_sub(count));
+
+        if events.len() >= count || window_start <= earliest {
+            break;
+        }
+
+        window_end = window_start - 1;
+        window *= 2;
+    }
+
+    Ok(events)
+}
+
+/// Fetch the events of each group concurrently, merging them in timestamp order and setting aside
+/// the errors of any groups that failed.
+async fn fetch_groups<'a, 's, S, F, Fut>(
+    sources: &'s HashMap<ClientContext, S>,
+    groups: &'a [WatchGroup],
+    fetch: F,
+) -> (Vec<LogEvent>, Vec<(&'a WatchGroup, SourceError)>)
+where
+    S: LogSource,
+    F: Fn(&'s S, &'a WatchGroup) -> Fut,
+    Fut: Future<Output = Result<Vec<LogEvent>, SourceError>>,
+{
+    let results = join_all(groups.iter().map(|group| {
+        let result = fetch(&sources[&group.context], group);
+        async move { (group, result.await) }
+    }))
+    .await;
+
+    let mut events = Vec::new();
+    let mut errors = Vec::new();
+    for (group, result) in results {
+        match result {
+            Ok(group_events) => events.extend(group_events),
+            Err(err) => errors.push((group, err)),
+        }
+    }
+
+    // The sort is stable, so events with the same timestamp stay in the order of their groups
+    events.sort_by_key(|event| event.timestamp);
+    (events, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        memory::MemoryLogSource,
+        source::SourceErrorKind,
+        test_support::{group, messages, sources},
+    };
+
+    #[tokio::test]
+    async fn merges_groups_within_range() {
+        let source = MemoryLogSource::new();
+        source.push_event("a", "s", 1_000, "too early");
+        source.push_event("a", "s", 2_000, "a first");
+        source.push_event("b", "s", 3_000, "b second");
+        source.push_event("a", "s", 4_000, "a third");
+        source.push_event("b", "s", 5_000, "b fourth");
+        source.push_event("b", "s", 6_000, "too late");
+
+        let groups = [group("a"), group("b")];
+        let mut events = Vec::new();
+        let errors = fetch_events(&sources(source), &groups, 2_000, 5_000, |event| {
+            events.push(event)
+        })
+        .await;
+
+        assert!(errors.is_empty());
+        assert_eq!(
+            messages(&events),
+            vec!["a first", "b second", "a third", "b fourth"]
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_events_as_pages_arrive() {
+        let source = MemoryLogSource::with_page_size(2);
+        for i in 0..5 {
+            source.push_event("a", "s", 1_000 + i * 10, &format!("a {}", i));
+            source.push_event("b", "s", 1_005 + i * 10, &format!("b {}", i));
+        }
+
+        // Note how many requests had been made when each event was written. A group's next page is
+        // only fetched once its last page has been merged.
+        let groups = [group("a"), group("b")];
+        let mut written = Vec::new();
+        let errors = fetch_events(&sources(source.clone()), &groups, 0, 10_000, |event| {
+            written.push((event.message, source.calls()))
+        })
+        .await;
+
+        assert!(errors.is_empty());
+        assert_eq!(
+            written,
+            vec![
+                ("a 0".to_string(), 2),
+                ("b 0".to_string(), 2),
+                ("a 1".to_string(), 2),
+                ("b 1".to_string(), 3),
+                ("a 2".to_string(), 4),
+                ("b 2".to_string(), 4),
+                ("a 3".to_string(), 4),
+                ("b 3".to_string(), 5),
+                ("a 4".to_string(), 6),
+                ("b 4".to_string(), 6),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn fetches_every_page() {
+        let source = MemoryLogSource::with_page_size(2);
+        for i in 0..25 {
+            source.push_event("a", "s", 1_000 + i, &format!("event {}", i));
+        }
+
+        let groups = [group("a")];
+        let mut count = 0;
+        fetch_events(&sources(source.clone()), &groups, 0, 10_000, |_| count += 1).await;
+
+        assert_eq!(count, 25);
+        assert_eq!(source.calls(), 13);
+    }
+
+    #[tokio::test]
+    async fn finds_most_recent_events() {
+        let source = MemoryLogSource::with_page_size(2);
+        let now = 1_000_000_000;
+        source.push_event("busy", "s", now - 50_000, "busy old");
+        for i in 0..5 {
+            source.push_event("busy", "s", now - 5_000 + i, &format!("busy {}", i));
+        }
+        source.push_event("quiet", "s", now - 30 * 24 * 60 * 60 * 1000, "quiet last");
+        source.push_event("quiet", "s", now + 1_000, "quiet future");
+
+        let groups = [group("busy"), group("quiet")];
+        let (events, errors) = recent_events(&sources(source), &groups, 4, now, |_| true).await;
+
+        assert!(errors.is_empty());
+        assert_eq!(
+            messages(&events),
+            vec!["busy 1", "busy 2", "busy 3", "busy 4"]
+        );
+    }
+
+    #[tokio::test]
+    async fn searches_back_for_quiet_groups() {
+        let source = MemoryLogSource::new();
+        let now = 1_000_000_000;
+        source.push_event("busy", "s", now - 1_000, "busy");
+        source.push_event("quiet", "s", now - 40 * 24 * 60 * 60 * 1000, "quiet first");
+        source.push_event("quiet", "s", now - 30 * 24 * 60 * 60 * 1000, "quiet last");
+
+        let groups = [group("busy"), group("quiet")];
+        let (events, _) = recent_events(&sources(source), &groups, 3, now, |_| true).await;
+
+        assert_eq!(messages(&events), vec!["quiet first", "quiet last", "busy"]);
+    }
+
+    #[tokio::test]
+    async fn counts_only_events_that_pass_the_filter() {
+        let source = MemoryLogSource::new();
+        let now = 1_000_000_000;
+        source.push_event("a", "s", now - 60_000, "ERROR first");
+        source.push_event("a", "s", now - 50_000, "ERROR second");
+        for i in 0..5 {
+            source.push_event("a", "s", now - 5_000 + i, &format!("INFO {}", i));
+        }
+
+        let groups = [group("a")];
+        let (events, _) = recent_events(&sources(source), &groups, 2, now, |event| {
+            event.message.starts_with("ERROR")
+        })
+        .await;
+
+        assert_eq!(messages(&events), vec!["ERROR first", "ERROR second"]);
+    }
+
+    #[tokio::test]
+    async fn limits_the_search_when_nothing_passes_the_filter() {
+        let source = MemoryLogSource::with_page_size(2);
+        let now = 1_000_000_000;
+        for i in 0..1_000 {
+            source.push_event("a", "s", now - i * 60 * 60 * 1000, "INFO busy");
+        }
+
+        let groups = [group("a")];
+        let (events, errors) = recent_events(&sources(source.clone()), &groups, 10, now, |event| {
+            event.message.starts_with("ERROR")
+        })
+        .await;
+
+        assert!(events.is_empty());
+        assert!(errors.is_empty());
+        assert_eq!(source.calls(), RECENT_EVENTS_MAX_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn narrows_windows_that_are_too_busy() {
+        let source = MemoryLogSource::with_page_size(5_000);
+        let now = 1_000_000_000;
+        for i in 0..(RECENT_EVENTS_WINDOW_EVENTS as i64 + 10) {
+            source.push_event("a", "s", now - 9_000 + i / 10, &i.to_string());
+        }
+
+        let groups = [group("a")];
+        let (events, errors) = recent_events(&sources(source), &groups, 2, now, |_| true).await;
+
+        assert!(errors.is_empty());
+        assert_eq!(messages(&events), vec!["10008", "10009"]);
+    }
+
+    #[tokio::test]
+    async fn stops_searching_back_eventually() {
+        let source = MemoryLogSource::new();
+        source.add_group("empty");
+
+        let groups = [group("empty")];
+        let (events, errors) =
+            recent_events(&sources(source.clone()), &groups, 10, 1_000_000_000, |_| {
+                true
+            })
+            .await;
+
+        assert!(events.is_empty());
+        assert!(errors.is_empty());
+        assert!(source.calls() < 30);
+    }
+
+    #[tokio::test]
+    async fn reports_failed_groups() {
+        let source = MemoryLogSource::new();
+        source.push_event("a", "s", 1_000, "hello");
+
+        let groups = [group("a"), group("missing")];
+        let mut events = Vec::new();
+        let errors = fetch_events(&sources(source), &groups, 0, 10_000, |event| {
+            events.push(event)
+        })
+        .await;
+
+        assert_eq!(messages(&events), vec!["hello"]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0.name, "missing");
+        assert_eq!(errors[0].1.kind, SourceErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,457 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Write},
+    future::Future,
+    time::Instant,
+};
+
+use futures::{future, StreamExt};
+use regex::Regex;
+
+use crate::{
+    event::LogEvent,
+    output::EventDisplay,
+    rules::Level,
+    source::LogSource,
+    watch::{WatchStats, Watcher},
+};
+
+/// The exit code for when we're stopped by Ctrl+c or SIGTERM, as the shell would give.
+pub const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+/// Conditions that end a watch early.
+#[derive(Debug, Default)]
+pub struct ExitConditions {
+    pub deadline: Option<Instant>,
+    /// Stop once this many events have been shown.
+    pub max_events: Option<usize>,
+    /// Stop once a shown event matches this pattern.
+    pub exit_on_match: Option<Regex>,
+}
+
+/// Why a watch ended, which decides our exit code.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchEnd {
+    /// An event matched `--exit-on-match`.
+    Matched,
+    /// `--max-events` events were shown.
+    MaxEvents,
+    /// The `--until` deadline passed.
+    Deadline,
+    /// Every group was given up on.
+    NoGroupsLeft,
+    /// We were asked to stop with Ctrl+c or SIGTERM.
+    Interrupted,
+}
+
+impl WatchEnd {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            WatchEnd::Matched => 0,
+            WatchEnd::NoGroupsLeft => 1,
+            WatchEnd::MaxEvents => 3,
+            WatchEnd::Deadline => 4,
+            WatchEnd::Interrupted => INTERRUPTED_EXIT_CODE,
+        }
+    }
+}
+
+impl Display for WatchEnd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WatchEnd::Matched => write!(f, "Stopped watching: an event matched --exit-on-match"),
+            WatchEnd::MaxEvents => write!(f, "Stopped watching: reached --max-events"),
+            WatchEnd::Deadline => write!(f, "Stopped watching: reached --until"),
+            WatchEnd::NoGroupsLeft => write!(f, "No log groups left to watch"),
+            WatchEnd::Interrupted => write!(f, "Stopped watching: interrupted"),
+        }
+    }
+}
+
+/// A count of the events that a watch has shown, for the summary.
+#[derive(Debug, Default)]
+pub struct ShownEvents {
+    /// The events shown from each group, by its label (see [`LogEvent::group_label`]).
+    pub groups: BTreeMap<String, usize>,
+    /// The events shown at each level, including those without one.
+    pub levels: BTreeMap<Option<Level>, usize>,
+}
+
+impl ShownEvents {
+    fn add(&mut self, group: String, level: Option<Level>) {
+        *self.groups.entry(group).or_default() += 1;
+        *self.levels.entry(level).or_default() += 1;
+    }
+
+    pub fn total(&self) -> usize {
+        self.groups.values().sum()
+    }
+
+    /// Describe what a watch did: the events shown from each group and at each level, the errors
+    /// for each group, the requests made and the event IDs held for deduplication.
+    pub fn summary(&self, stats: &WatchStats) -> String {
+        fn counts(
+            summary: &mut String,
+            title: &str,
+            counts: impl Iterator<Item = (String, usize)>,
+        ) {
+            let counts: Vec<_> = counts.collect();
+            let width = counts
+                .iter()
+                .map(|(label, _)| label.chars().count())
+                .max()
+                .unwrap_or_default();
+
+            writeln!(summary, "{}:", title).unwrap();
+            if counts.is_empty() {
+                writeln!(summary, "  none").unwrap();
+            }
+            for (label, count) in counts {
+                writeln!(summary, "  {:width$}  {}", label, count, width = width).unwrap();
+            }
+        }
+
+        // Every group is listed, including those that showed nothing
+        let mut summary = String::new();
+        counts(
+            &mut summary,
+            "Events by group",
+            stats
+                .errors
+                .iter()
+                .map(|(group, _)| (group.clone(), self.groups.get(group).copied().unwrap_or(0))),
+        );
+        counts(
+            &mut summary,
+            "Events by level",
+            self.levels.iter().rev().map(|(level, count)| {
+                let level = level.map_or_else(|| "none".to_string(), |level| level.to_string());
+                (level, *count)
+            }),
+        );
+        counts(
+            &mut summary,
+            "Errors by group",
+            stats
+                .errors
+                .iter()
+                .filter(|(_, errors)| *errors > 0)
+                .cloned(),
+        );
+        writeln!(summary, "API calls: {}", stats.requests).unwrap();
+        write!(
+            summary,
+            "Event IDs held for deduplication: {} ({} forgotten)",
+            stats.seen_events.held, stats.seen_events.forgotten
+        )
+        .unwrap();
+        summary
+    }
+}
+
+/// Show the last `lines` events (if given), then follow new events from the watcher, passing each
+/// line that the display renders to `output`. This carries on until one of the exit conditions is
+/// met, every group is given up on, or `shutdown` completes. Any poll that's in progress when we
+/// stop is abandoned.
+pub async fn follow<S: LogSource>(
+    watcher: &mut Watcher<S>,
+    lines: Option<usize>,
+    display: &EventDisplay,
+    exit: &ExitConditions,
+    shutdown: impl Future<Output = ()>,
+    mut output: impl FnMut(String),
+    shown: &mut ShownEvents,
+) -> WatchEnd {
+    // Show the event if it isn't filtered out, and check whether that ends the watch
+    let show_region = watcher.show_region();
+    let mut show = |event: LogEvent| {
+        let matched = exit
+            .exit_on_match
+            .as_ref()
+            .is_some_and(|pattern| pattern.is_match(&event.message));
+        let group = event.group_label(show_region);
+        let level = display.level(&event);
+        output(display.render(event)?);
+        shown.add(group, level);
+
+        if matched {
+            Some(WatchEnd::Matched)
+        } else if exit
+            .max_events
+            .is_some_and(|max_events| shown.total() >= max_events)
+        {
+            Some(WatchEnd::MaxEvents)
+        } else {
+            None
+        }
+    };
+
+    let deadline = async {
+        match exit.deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+            None => future::pending().await,
+        }
+    };
+    tokio::pin!(deadline);
+    tokio::pin!(shutdown);
+
+    if let Some(lines) = lines {
+        let backlog = tokio::select! {
+            backlog = watcher.backlog(lines, |event| display.matches(event)) => backlog,
+            _ = &mut deadline => return WatchEnd::Deadline,
+            _ = &mut shutdown => return WatchEnd::Interrupted,
+        };
+        for event in backlog {
+            if let Some(end) = show(event) {
+                return end;
+            }
+        }
+    }
+
+    let mut events = Box::pin(watcher.stream());
+    loop {
+        let event = tokio::select! {
+            event = events.next() => event,
+            _ = &mut deadline => return WatchEnd::Deadline,
+            _ = &mut shutdown => return WatchEnd::Interrupted,
+        };
+        match event {
+            Some(event) => {
+                if let Some(end) = show(event) {
+                    return end;
+                }
+            }
+            None => return WatchEnd::NoGroupsLeft,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use time::OffsetDateTime;
+
+    use super::*;
+    use crate::{
+        config::ColorConfig,
+        memory::MemoryLogSource,
+        output::{DisplayOptions, OutputFormat},
+        test_support::{group, seconds_ago, sources},
+        watch::{PollBudget, WatcherSettings, DEFAULT_POLL_OVERLAP},
+    };
+
+    fn watcher(source: MemoryLogSource, groups: &[&str]) -> Watcher<MemoryLogSource> {
+        Watcher::new(
+            sources(source),
+            groups.iter().map(|name| group(name)).collect(),
+            WatcherSettings {
+                refresh: Duration::from_secs(60),
+                budget: PollBudget::default(),
+                start_time: OffsetDateTime::now_utc() - Duration::from_secs(600),
+                max_not_found: 1,
+                overlap: DEFAULT_POLL_OVERLAP,
+            },
+        )
+    }
+
+    /// Follow the watcher, returning why we stopped and the messages that were shown.
+    async fn follow_messages(
+        watcher: &mut Watcher<MemoryLogSource>,
+        lines: Option<usize>,
+        exit: ExitConditions,
+        shutdown: impl Future<Output = ()>,
+    ) -> (WatchEnd, Vec<String>) {
+        let (end, messages, _) = follow_shown(watcher, lines, exit, shutdown).await;
+        (end, messages)
+    }
+
+    /// Follow the watcher as [`follow_messages`] does, also returning the count of shown events.
+    async fn follow_shown(
+        watcher: &mut Watcher<MemoryLogSource>,
+        lines: Option<usize>,
+        exit: ExitConditions,
+        shutdown: impl Future<Output = ()>,
+    ) -> (WatchEnd, Vec<String>, ShownEvents) {
+        let display = EventDisplay::new(
+            DisplayOptions {
+                output: Some(OutputFormat::Json),
+                ..DisplayOptions::default()
+            },
+            &ColorConfig::default(),
+            None,
+            false,
+        )
+        .unwrap();
+
+        let mut messages = Vec::new();
+        let mut shown = ShownEvents::default();
+        let end = follow(
+            watcher,
+            lines,
+            &display,
+            &exit,
+            shutdown,
+            |line| {
+                let event: serde_json::Value = serde_json::from_str(&line).unwrap();
+                messages.push(event["message"].as_str().unwrap().to_string());
+            },
+            &mut shown,
+        )
+        .await;
+        (end, messages, shown)
+    }
+
+    #[tokio::test]
+    async fn stops_on_a_matching_event() {
+        let source = MemoryLogSource::new();
+        source.push_event("a", "s", seconds_ago(30), "starting");
+        source.push_event("a", "s", seconds_ago(20), "deployment complete");
+        source.push_event("a", "s", seconds_ago(10), "after");
+
+        let exit = ExitConditions {
+            exit_on_match: Some(Regex::new("complete").unwrap()),
+            ..ExitConditions::default()
+        };
+        let (end, messages) =
+            follow_messages(&mut watcher(source, &["a"]), None, exit, future::pending()).await;
+
+        assert_eq!(end, WatchEnd::Matched);
+        assert_eq!(messages, vec!["starting", "deployment complete"]);
+    }
+
+    #[tokio::test]
+    async fn stops_after_max_events() {
+        let source = MemoryLogSource::new();
+        source.push_event("a", "s", seconds_ago(30), "one");
+        source.push_event("a", "s", seconds_ago(20), "two");
+        source.push_event("a", "s", seconds_ago(10), "three");
+
+        let mut watcher = watcher(source, &["a"]);
+        let exit = ExitConditions {
+            max_events: Some(2),
+            ..ExitConditions::default()
+        };
+        let (end, messages) = follow_messages(&mut watcher, None, exit, future::pending()).await;
+
+        assert_eq!(end, WatchEnd::MaxEvents);
+        assert_eq!(messages, vec!["one", "two"]);
+    }
+
+    #[tokio::test]
+    async fn counts_the_backlog_towards_max_events() {
+        let source = MemoryLogSource::new();
+        source.push_event("a", "s", seconds_ago(900), "old");
+        source.push_event("a", "s", seconds_ago(800), "older backlog");
+        source.push_event("a", "s", seconds_ago(700), "backlog");
+
+        let exit = ExitConditions {
+            max_events: Some(2),
+            ..ExitConditions::default()
+        };
+        let (end, messages) = follow_messages(
+            &mut watcher(source, &["a"]),
+            Some(2),
+            exit,
+            future::pending(),
+        )
+        .await;
+
+        assert_eq!(end, WatchEnd::MaxEvents);
+        assert_eq!(messages, vec!["older backlog", "backlog"]);
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_deadline() {
+        let source = MemoryLogSource::new();
+        source.push_event("a", "s", seconds_ago(10), "hello");
+
+        let exit = ExitConditions {
+            deadline: Some(Instant::now() + Duration::from_millis(50)),
+            ..ExitConditions::default()
+        };
+        let (end, messages) =
+            follow_messages(&mut watcher(source, &["a"]), None, exit, future::pending()).await;
+
+        assert_eq!(end, WatchEnd::Deadline);
+        assert_eq!(messages, vec!["hello"]);
+    }
+
+    #[tokio::test]
+    async fn stops_when_no_groups_are_left() {
+        let (end, messages) = follow_messages(
+            &mut watcher(MemoryLogSource::new(), &["missing"]),
+            None,
+            ExitConditions::default(),
+            future::pending(),
+        )
+        .await;
+
+        assert_eq!(end, WatchEnd::NoGroupsLeft);
+        assert!(messages.is_empty());
+    }
+
+    #[tokio::test]
+    async fn stops_when_interrupted() {
+        let source = MemoryLogSource::new();
+        source.add_group("a");
+
+        let (end, _) = follow_messages(
+            &mut watcher(source, &["a"]),
+            None,
+            ExitConditions::default(),
+            future::ready(()),
+        )
+        .await;
+
+        assert_eq!(end, WatchEnd::Interrupted);
+    }
+
+    #[tokio::test]
+    async fn summarises_the_watch() {
+        let source = MemoryLogSource::new();
+        source.push_event("a", "s", seconds_ago(30), "ERROR one");
+        source.push_event("a", "s", seconds_ago(20), "two");
+        source.push_event("b", "s", seconds_ago(10), "ERROR three");
+
+        let mut watcher = watcher(source, &["a", "missing", "b", "a"]);
+        let exit = ExitConditions {
+            max_events: Some(3),
+            ..ExitConditions::default()
+        };
+        let (_, _, shown) = follow_shown(&mut watcher, None, exit, future::pending()).await;
+
+        assert_eq!(
+            shown.summary(&watcher.stats()),
+            [
+                "Events by group:",
+                "  a        2",
+                "  missing  0",
+                "  b        1",
+                "Events by level:",
+                "  error  2",
+                "  none   1",
+                "Errors by group:",
+                "  missing  1",
+                "API calls: 3",
+                "Event IDs held for deduplication: 3 (0 forgotten)",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn exit_codes_tell_the_ends_apart() {
+        let codes: Vec<i32> = [
+            WatchEnd::Matched,
+            WatchEnd::NoGroupsLeft,
+            WatchEnd::MaxEvents,
+            WatchEnd::Deadline,
+            WatchEnd::Interrupted,
+        ]
+        .iter()
+        .map(|end| end.exit_code())
+        .collect();
+        assert_eq!(codes, vec![0, 1, 3, 4, 130]);
+    }
+}
@@ -0,0 +1,30 @@
+//! Watch one or more CloudWatch log groups.
+//!
+//! This is the library behind the `cloudwatcher` binary. A [`Watcher`](watch::Watcher) polls a set
+//! of [`WatchGroup`](watch::WatchGroup)s through a [`LogSource`](source::LogSource), producing a
+//! stream of deduplicated [`LogEvent`]s that can be rendered with a [`Formatter`].
+
+pub mod config;
+pub mod context;
+pub mod event;
+pub mod fetch;
+pub mod follow;
+pub mod memory;
+pub mod message;
+pub mod output;
+pub mod predicate;
+pub mod query;
+pub mod range;
+pub mod rules;
+pub mod source;
+pub mod watch;
+
+#[cfg(test)]
+mod test_support;
+
+pub use crate::{
+    event::LogEvent,
+    output::Formatter,
+    source::{LogSource, SdkLogSource},
+    watch::{WatchGroup, Watcher},
+};
@@ -1,12 +1,40 @@
-use std::{collections::HashSet, time::Duration};
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    fmt::Display,
+    io::{self, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
 
-use aws_config::meta::region::RegionProviderChain;
-use aws_sdk_cloudwatchlogs::{Client, Error, Region};
-use console::Style;
-use futures::{stream::FuturesUnordered, StreamExt};
+use aws_config::{default_provider::region::DefaultRegionChain, meta::region::RegionProviderChain};
+use aws_sdk_cloudwatchlogs::{Client, Region};
+use aws_types::SdkConfig;
 use gumdrop::Options;
+use http::Uri;
 use humantime::parse_duration;
-use time::{format_description, OffsetDateTime};
+use time::OffsetDateTime;
+
+use cloudwatcher::{
+    config::{Config, WatchSet},
+    context::{account_id, ClientContext},
+    event::unix_millis,
+    fetch::fetch_events,
+    follow::{follow, ExitConditions, ShownEvents, WatchEnd, INTERRUPTED_EXIT_CODE},
+    message::{FieldProjection, JsonMode},
+    output::{DisplayOptions, EventDisplay, OutputFormat},
+    query::{query_groups, GroupQuery, QueryFormat, QUERY_POLL_INTERVAL},
+    range::{parse_start_time, parse_time_range, parse_until},
+    rules::Level,
+    source::{self, LogSource, SdkLogSource, SourceError},
+    watch::{
+        parse_group_filters, resolve_groups, show_region, GroupFilters, PollBudget, WatchGroup,
+        Watcher, WatcherSettings, DEFAULT_POLL_OVERLAP,
+    },
+};
+use console::Term;
+use futures::future;
+use regex::Regex;
+use tokio::sync::watch;
 
 #[derive(Debug, Options)]
 struct CloudWatcherOptions {
@@ -14,194 +42,852 @@ struct CloudWatcherOptions {
     help: bool,
     #[options(help = "override region")]
     region: Option<String>,
+    #[options(help = "use credentials from this named AWS profile")]
+    profile: Option<String>,
+    #[options(help = "configuration file (default: ~/.config/cloudwatcher/config.toml)")]
+    config: Option<String>,
+    #[options(
+        no_short,
+        help = "use this endpoint instead of AWS (default: $CLOUDWATCHER_ENDPOINT_URL)"
+    )]
+    endpoint_url: Option<String>,
+    #[options(help = "log more detail about what we're doing")]
+    verbose: bool,
     #[options(command)]
     command: Option<CloudWatcherCommands>,
 }
 
+// The watch options are much larger than the others, but we only ever have the one of these
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Options, PartialEq)]
 enum CloudWatcherCommands {
     #[options(help = "list cloudwatch log groups")]
     List(CloudWatcherListOptions),
     #[options(help = "watch logs from cloudwatch log groups")]
     Watch(CloudWatcherWatchOptions),
+    #[options(help = "fetch logs from a range of time and exit")]
+    Fetch(CloudWatcherFetchOptions),
+    #[options(help = "run a logs insights query")]
+    Query(CloudWatcherQueryOptions),
 }
 
 #[derive(Debug, Options, PartialEq)]
 struct CloudWatcherListOptions {
     #[options(help = "print help message")]
     help: bool,
+    #[options(help = "only list log groups with this name prefix")]
+    prefix: Option<String>,
+    #[options(help = "maximum number of log groups to list")]
+    limit: Option<usize>,
+}
+
+/// Declare the options of a command that shows events, followed by the options that select and
+/// display those events, which `watch` and `fetch` share. gumdrop can't flatten one set of options
+/// into another, so this stands in for it. Types are matched as plain tokens, as gumdrop needs to
+/// see `Option` and `Vec` to know how each option is parsed.
+macro_rules! event_options {
+    (
+        $(#[$attr:meta])*
+        struct $name:ident {
+            $($(#[$field_attr:meta])* $field:ident: $ty:ident$(<$param:ident>)?,)*
+        }
+    ) => {
+        $(#[$attr])*
+        struct $name {
+            $($(#[$field_attr])* $field: $ty$(<$param>)?,)*
+            #[options(help = "only show events matching this CloudWatch filter pattern")]
+            filter: Option<String>,
+            #[options(
+                no_short,
+                help = "only show events at or above this level: trace, debug, info, warn, error or fatal"
+            )]
+            level: Option<Level>,
+            #[options(
+                no_short,
+                long = "where",
+                meta = "EXPR",
+                help = "only show structured events matching this expression, such as 'status >= 500'"
+            )]
+            where_: Option<String>,
+            #[options(
+                no_short,
+                help = "filter pattern for a single group, as GROUP=PATTERN (overrides --filter)"
+            )]
+            group_filter: Vec<String>,
+            #[options(no_short, help = "only show events from streams with this name prefix")]
+            stream_prefix: Option<String>,
+            #[options(
+                no_short,
+                help = "only show events from this log stream (may be repeated)"
+            )]
+            stream: Vec<String>,
+            #[options(no_short, help = "show the log stream name of each event")]
+            show_stream: bool,
+            #[options(help = "output format: text or json (default: text)")]
+            output: Option<OutputFormat>,
+            #[options(no_short, help = "render JSON in messages: pretty or compact")]
+            json: Option<JsonMode>,
+            #[options(
+                no_short,
+                help = "only show these comma-separated fields of JSON and logfmt messages"
+            )]
+            fields: Option<FieldProjection>,
+            #[options(
+                no_short,
+                help = "style messages matching a regex, as PATTERN=STYLE (may be repeated)"
+            )]
+            rule: Vec<String>,
+            #[options(
+                no_short,
+                help = "style only the text matching a regex, as PATTERN=STYLE (may be repeated)"
+            )]
+            highlight: Vec<String>,
+        }
+
+        impl $name {
+            /// The options that select and display events.
+            fn event_options(&self) -> EventOptions {
+                EventOptions {
+                    filter: self.filter.clone(),
+                    group_filter: self.group_filter.clone(),
+                    stream_prefix: self.stream_prefix.clone(),
+                    stream: self.stream.clone(),
+                    display: DisplayOptions {
+                        level: self.level,
+                        where_: self.where_.clone(),
+                        show_stream: self.show_stream,
+                        output: self.output,
+                        json: self.json,
+                        fields: self.fields.clone(),
+                        rule: self.rule.clone(),
+                        highlight: self.highlight.clone(),
+                    },
+                }
+            }
+        }
+    };
+}
+
+/// The options that select and display events, which `watch` and `fetch` share.
+struct EventOptions {
+    filter: Option<String>,
+    group_filter: Vec<String>,
+    stream_prefix: Option<String>,
+    stream: Vec<String>,
+    display: DisplayOptions,
+}
+
+event_options! {
+    #[derive(Debug, Options, PartialEq)]
+    struct CloudWatcherWatchOptions {
+        #[options(help = "print help message")]
+        help: bool,
+        #[options(free, help = "cloudwatch groups to watch, as [PROFILE@][REGION:]GROUP")]
+        groups: Vec<String>,
+        #[options(help = "watch the named set of groups from the configuration file")]
+        set: Option<String>,
+        #[options(help = "refresh interval (default: 10s)")]
+        refresh: Option<String>,
+        #[options(help = "show events from this long ago (default: 10m, 0 for only new events)")]
+        since: Option<String>,
+        #[options(help = "show events from this RFC 3339 timestamp")]
+        from: Option<String>,
+        #[options(
+            short = "n",
+            help = "show the last N events across all groups, then follow new ones"
+        )]
+        lines: Option<usize>,
+        #[options(
+            no_short,
+            help = "stop watching after this long, or at this RFC 3339 timestamp (exit code 4)"
+        )]
+        until: Option<String>,
+        #[options(
+            no_short,
+            help = "stop watching after showing this many events (exit code 3)"
+        )]
+        max_events: Option<usize>,
+        #[options(
+            no_short,
+            help = "stop watching when a shown event matches this regex (exit code 0)"
+        )]
+        exit_on_match: Option<String>,
+        #[options(
+            no_short,
+            help = "when we stop, print the events per group and level, errors and API calls"
+        )]
+        summary: bool,
+        #[options(
+            no_short,
+            help = "stop watching a group after it is not found this many times (default: 3)"
+        )]
+        max_not_found: Option<usize>,
+        #[options(
+            no_short,
+            help = "how far before the latest event each poll looks for late events (default: 30s)"
+        )]
+        overlap: Option<String>,
+        #[options(no_short, help = "max pages per group each poll (default: 10)")]
+        max_pages: Option<usize>,
+        #[options(no_short, help = "max events per group each poll (default: 1000)")]
+        max_poll_events: Option<usize>,
+    }
+}
+
+event_options! {
+    #[derive(Debug, Options, PartialEq)]
+    struct CloudWatcherFetchOptions {
+        #[options(help = "print help message")]
+        help: bool,
+        #[options(
+            free,
+            help = "cloudwatch groups to fetch from, as [PROFILE@][REGION:]GROUP"
+        )]
+        groups: Vec<String>,
+        #[options(help = "fetch from the named set of groups from the configuration file")]
+        set: Option<String>,
+        #[options(help = "fetch events from this long ago")]
+        since: Option<String>,
+        #[options(help = "fetch events from this RFC 3339 timestamp")]
+        from: Option<String>,
+        #[options(help = "fetch events up to this RFC 3339 timestamp (default: now)")]
+        to: Option<String>,
+    }
 }
 
 #[derive(Debug, Options, PartialEq)]
-struct CloudWatcherWatchOptions {
+struct CloudWatcherQueryOptions {
     #[options(help = "print help message")]
     help: bool,
-    #[options(free, help = "cloudwatch groups to watch")]
+    #[options(free, help = "the logs insights query to run")]
+    query: Vec<String>,
+    #[options(
+        help = "log groups to query, as [PROFILE@][REGION:]GROUP (may be repeated or comma-separated)"
+    )]
     groups: Vec<String>,
-    #[options(help = "refresh interval (default: 10s)")]
-    refresh: Option<String>,
+    #[options(help = "query the named set of groups from the configuration file")]
+    set: Option<String>,
+    #[options(help = "query events from this long ago (default: 1h)")]
+    since: Option<String>,
+    #[options(help = "query events from this RFC 3339 timestamp")]
+    from: Option<String>,
+    #[options(help = "query events up to this RFC 3339 timestamp (default: now)")]
+    to: Option<String>,
+    #[options(help = "maximum number of results (default: 1000)")]
+    limit: Option<usize>,
+    #[options(help = "output format: table, json or csv (default: table)")]
+    output: Option<QueryFormat>,
 }
 
-async fn list_log_groups(client: &Client) -> Result<(), Error> {
-    let res = client.describe_log_groups().send().await?;
-    let groups = res.log_groups.unwrap_or_default();
-
-    for group in &groups {
-        println!("{}", group.log_group_name().unwrap_or_default());
+/// Get the names of the log groups with the given prefix and print them out.
+async fn list_log_groups<S: LogSource>(
+    source: &S,
+    prefix: Option<String>,
+    limit: Option<usize>,
+) -> Result<(), SourceError> {
+    let names = source::list_log_groups(source, prefix, limit).await?;
+    for name in &names {
+        println!("{}", name);
     }
 
-    println!("Found {} log groups", groups.len());
+    println!("Found {} log groups", names.len());
     Ok(())
 }
 
-struct LogEvent {
-    event_id: String,
-    group: String,
-    timestamp: OffsetDateTime,
-    message: String,
-}
-
-async fn get_group_events(
-    client: &Client,
-    group: &str,
-    start_time: i64,
-) -> Result<Vec<LogEvent>, Error> {
-    let res = client
-        .filter_log_events()
-        .log_group_name(group)
-        .limit(100)
-        .start_time(start_time)
-        .send()
-        .await?;
-
-    Ok(res
-        .events
-        .unwrap_or_default()
-        .into_iter()
-        .map(|event| LogEvent {
-            event_id: event.event_id.unwrap_or_default(),
-            group: group.to_string(),
-            timestamp: OffsetDateTime::from_unix_timestamp_nanos(
-                event.timestamp.unwrap_or_default() as i128 * 1_000_000,
-            )
-            .expect("Failed to parse timestamp"),
-            message: event
-                .message
-                .map(|msg| msg.trim().to_string())
-                .unwrap_or_default(),
-        })
-        .collect())
-}
-
-async fn watch_log_groups(
-    client: &Client,
-    group_names: Vec<String>,
-    refresh: Duration,
-) -> Result<(), Error> {
-    let format = format_description::parse(
-        "[year]-[month]-[day] [hour]:[minute]:[second]:[subsecond digits:6]",
-    )
-    .unwrap();
-    let mut seen_events: HashSet<String> = HashSet::new();
-
-    let def = Style::new();
-    let red = Style::new().red();
-    let green = Style::new().green();
-    let blue = Style::new().blue();
-    let magenta = Style::new().magenta();
-    let yellow = Style::new().yellow();
-
-    loop {
-        let start_time =
-            (OffsetDateTime::now_utc() - Duration::from_secs(600)).unix_timestamp() * 1000;
-        let queries = FuturesUnordered::new();
-        for group in &group_names {
-            queries.push(get_group_events(client, &group, start_time));
+/// Follow the groups until the watch ends, reporting problems with the groups and why the watch
+/// ended on stderr.
+async fn watch_log_groups<S: LogSource>(
+    mut watcher: Watcher<S>,
+    lines: Option<usize>,
+    display: &EventDisplay,
+    exit: ExitConditions,
+    mut shutdown: watch::Receiver<bool>,
+    summary: bool,
+) -> WatchEnd {
+    let interrupted = async {
+        // The Ctrl+c handler keeps the sender for as long as we run
+        if shutdown.changed().await.is_err() {
+            future::pending::<()>().await;
         }
+    };
 
-        let results = queries.collect::<Vec<_>>().await;
-        let mut new_events = Vec::new();
+    watcher.on_status(|status| eprintln!("{}", status));
 
-        for result in results {
-            for event in result.unwrap_or_default() {
-                if seen_events.insert(event.event_id.to_string()) {
-                    new_events.push(event);
-                }
+    let mut shown = ShownEvents::default();
+    let end = follow(
+        &mut watcher,
+        lines,
+        display,
+        &exit,
+        interrupted,
+        |line| println!("{}", line),
+        &mut shown,
+    )
+    .await;
+
+    // Make sure that everything we've shown is written out before we exit
+    io::stdout().flush().ok();
+
+    eprintln!("{}", end);
+    if summary {
+        eprintln!("{}", shown.summary(&watcher.stats()));
+    }
+
+    end
+}
+
+/// Fetch every event in the time range from the groups and print those that aren't filtered out,
+/// in timestamp order. Returns whether every group could be fetched from.
+async fn fetch_log_groups<S: LogSource>(
+    sources: &HashMap<ClientContext, S>,
+    groups: &[WatchGroup],
+    start_time: OffsetDateTime,
+    end_time: OffsetDateTime,
+    display: &EventDisplay,
+) -> bool {
+    let mut count = 0;
+    let errors = fetch_events(
+        sources,
+        groups,
+        unix_millis(start_time),
+        unix_millis(end_time),
+        |event| {
+            if let Some(line) = display.render(event) {
+                println!("{}", line);
+                count += 1;
             }
+        },
+    )
+    .await;
+
+    for (group, err) in &errors {
+        eprintln!("Failed to fetch {}: {}", group, err);
+    }
+
+    eprintln!(
+        "Fetched {} events from {} log groups",
+        count,
+        groups.len() - errors.len()
+    );
+    errors.is_empty()
+}
+
+/// Merge the groups and filters from the watch set with those from the command-line, which take
+/// precedence, and set up how their events are displayed. Each group is given a source, and a label
+/// for its account if there's more than one. Mistakes in the options are reported as usage errors.
+async fn prepare_groups(
+    specs: Vec<String>,
+    options: EventOptions,
+    watch_set: WatchSet,
+    config: &Config,
+    default_context: &ClientContext,
+    sdk_config: SdkConfig,
+) -> (
+    Vec<WatchGroup>,
+    EventDisplay,
+    HashMap<ClientContext, SdkLogSource>,
+) {
+    let EventOptions {
+        filter,
+        group_filter,
+        stream_prefix,
+        stream,
+        display,
+    } = options;
+    let WatchSet {
+        groups: set_groups,
+        filter: set_filter,
+        colors: set_colors,
+        rules: set_rules,
+        ..
+    } = watch_set;
+
+    let specs: Vec<String> = set_groups.into_iter().chain(specs).collect();
+    if specs.is_empty() {
+        usage_error("No log groups given");
+    }
+
+    if stream_prefix.is_some() && !stream.is_empty() {
+        usage_error("Only one of --stream-prefix and --stream can be given");
+    }
+
+    let group_filters = match parse_group_filters(&group_filter) {
+        Ok(group_filters) => group_filters,
+        Err(err) => usage_error(err),
+    };
+
+    let mut groups = resolve_groups(
+        &specs,
+        default_context,
+        &GroupFilters {
+            filter: filter.or(set_filter),
+            group_filters,
+            stream_prefix,
+            stream_names: stream,
+        },
+    );
+
+    // Rules from the watch set come before the global rules
+    let display = match EventDisplay::new(
+        display,
+        &set_colors,
+        set_rules.into_iter().chain(config.rules.iter().cloned()),
+        show_region(&groups),
+    ) {
+        Ok(display) => display,
+        Err(err) => usage_error(err),
+    };
+
+    let sdk_configs = load_groups(&mut groups, default_context, sdk_config, &config.accounts).await;
+    (groups, display, sdk_sources(&sdk_configs))
+}
+
+/// Load the configuration for each combination of credentials and region that we don't have yet.
+async fn load_sdk_configs<'a>(
+    sdk_configs: &mut HashMap<ClientContext, SdkConfig>,
+    contexts: impl Iterator<Item = &'a ClientContext>,
+) {
+    for context in contexts {
+        if !sdk_configs.contains_key(context) {
+            sdk_configs.insert(context.clone(), context.load().await);
         }
+    }
+}
+
+/// Get a label for each set of credentials when we're using more than one of them, so that events
+/// can be told apart. This is the account ID, or any alias for it from the configuration.
+async fn account_labels(
+    groups: &[WatchGroup],
+    sdk_configs: &HashMap<ClientContext, SdkConfig>,
+    aliases: &HashMap<String, String>,
+) -> HashMap<String, String> {
+    let mut accounts = HashMap::new();
+    let credentials = groups
+        .iter()
+        .map(|group| group.context.credentials_name())
+        .collect::<HashSet<_>>();
+    if credentials.len() < 2 {
+        return accounts;
+    }
 
-        new_events.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
-        for event in new_events {
-            let timestamp = event.timestamp.format(&format).unwrap();
-            let message = if event.message.contains("INFO") {
-                blue.apply_to(event.message)
-            } else if event.message.contains("ERROR") {
-                red.apply_to(event.message)
-            } else if event.message.contains("WARN") {
-                yellow.apply_to(event.message)
-            } else {
-                def.apply_to(event.message)
-            };
-
-            println!(
-                "{} {}: {}",
-                green.apply_to(timestamp),
-                magenta.apply_to(event.group),
-                message
-            )
+    for group in groups {
+        if let Entry::Vacant(entry) = accounts.entry(group.context.credentials_name().to_string()) {
+            let account = account_id(&sdk_configs[&group.context])
+                .await
+                .map(|id| aliases.get(&id).cloned().unwrap_or(id))
+                .unwrap_or_else(|| entry.key().clone());
+            entry.insert(account);
         }
+    }
+
+    accounts
+}
+
+/// Load the configuration for each combination of credentials and region used by the groups, and
+/// label the groups with their accounts.
+async fn load_groups(
+    groups: &mut [WatchGroup],
+    default_context: &ClientContext,
+    sdk_config: SdkConfig,
+    aliases: &HashMap<String, String>,
+) -> HashMap<ClientContext, SdkConfig> {
+    let mut sdk_configs = HashMap::new();
+    sdk_configs.insert(default_context.clone(), sdk_config);
+    load_sdk_configs(&mut sdk_configs, groups.iter().map(|group| &group.context)).await;
 
-        tokio::time::sleep(refresh).await;
+    let accounts = account_labels(groups, &sdk_configs, aliases).await;
+    for group in groups.iter_mut() {
+        group.account = accounts.get(group.context.credentials_name()).cloned();
     }
+
+    sdk_configs
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Error> {
-    // Gracefully terminate when we receive Ctrl+c
-    ctrlc::set_handler(move || std::process::exit(0)).expect("Could not set Ctrl+c handler");
+/// Create a log source for each combination of credentials and region.
+fn sdk_sources(
+    sdk_configs: &HashMap<ClientContext, SdkConfig>,
+) -> HashMap<ClientContext, SdkLogSource> {
+    sdk_configs
+        .iter()
+        .map(|(context, sdk_config)| (context.clone(), SdkLogSource::new(Client::new(sdk_config))))
+        .collect()
+}
+
+/// Run a Logs Insights query against the groups and print the results, returning whether every
+/// query succeeded.
+async fn query_log_groups<S: LogSource>(
+    sources: &HashMap<ClientContext, S>,
+    groups: &[WatchGroup],
+    query: GroupQuery,
+    format: QueryFormat,
+) -> bool {
+    // Show our progress on stderr while the queries run, if there's someone there to see it
+    let term = Term::stderr();
+    let started = Instant::now();
+    let (table, errors) = query_groups(
+        sources,
+        groups,
+        &query,
+        QUERY_POLL_INTERVAL,
+        |matched, scanned| {
+            if term.is_term() {
+                term.clear_line().ok();
+                term.write_str(&format!(
+                    "Running query ({}s): {} records matched, {} scanned",
+                    started.elapsed().as_secs(),
+                    matched,
+                    scanned
+                ))
+                .ok();
+            }
+        },
+    )
+    .await;
+
+    if term.is_term() {
+        term.clear_line().ok();
+    }
+
+    for (source, err) in &errors {
+        eprintln!("Query failed in {}: {}", source, err);
+    }
+
+    if !table.rows.is_empty() || format == QueryFormat::Csv {
+        println!("{}", table.render(format));
+    }
+    if format == QueryFormat::Table {
+        eprintln!("{} results", table.rows.len());
+    }
+
+    errors.is_empty()
+}
 
+/// The exit code for a mistake in the command-line or configuration, as for gumdrop's own errors.
+const USAGE_EXIT_CODE: i32 = 2;
+
+/// Report a mistake in the command-line or configuration on stderr, and exit.
+fn usage_error(message: impl Display) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(USAGE_EXIT_CODE);
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Parse the command-line arguments
     let options: CloudWatcherOptions = CloudWatcherOptions::parse_args_default_or_exit();
 
-    // Figure out our region
-    let region_provider = RegionProviderChain::first_try(options.region.map(Region::new))
-        .or_default_provider()
+    // A watch stops gracefully when we receive Ctrl+c or SIGTERM, unless it's the second time we've
+    // been asked. Anything else can stop straight away.
+    let graceful = matches!(options.command, Some(CloudWatcherCommands::Watch(_)));
+    let (shutdown_sender, shutdown) = watch::channel(false);
+    ctrlc::set_handler(move || {
+        if !graceful || shutdown_sender.send_replace(true) {
+            std::process::exit(INTERRUPTED_EXIT_CODE);
+        }
+    })
+    .expect("Could not set Ctrl+c handler");
+
+    // Load the configuration file
+    let mut config = match Config::load(options.config.as_deref().map(Path::new)) {
+        Ok(config) => config,
+        Err(err) => usage_error(format!("Failed to load configuration: {}", err)),
+    };
+
+    // Find the watch set we've been asked for, as its region feeds into our region provider
+    let watch_set = match &options.command {
+        Some(CloudWatcherCommands::Watch(CloudWatcherWatchOptions {
+            set: Some(name), ..
+        }))
+        | Some(CloudWatcherCommands::Fetch(CloudWatcherFetchOptions {
+            set: Some(name), ..
+        }))
+        | Some(CloudWatcherCommands::Query(CloudWatcherQueryOptions {
+            set: Some(name), ..
+        })) => match config.sets.remove(name) {
+            Some(watch_set) => watch_set,
+            None => usage_error(format!(
+                "No watch set named '{}' in the configuration",
+                name
+            )),
+        },
+        _ => WatchSet::default(),
+    };
+
+    // Figure out our profile and region, preferring the command-line over the watch set
+    let profile = options.profile.or_else(|| watch_set.profile.clone());
+    let region = options.region.or_else(|| watch_set.region.clone());
+    let mut default_region_chain = DefaultRegionChain::builder();
+    if let Some(profile) = &profile {
+        default_region_chain = default_region_chain.profile_name(profile);
+    }
+    let region_provider = RegionProviderChain::first_try(region.map(Region::new))
+        .or_else(default_region_chain.build())
         .or_else(Region::new("eu-west-1"));
 
-    // Set up our logger
-    env_logger::init();
+    // Set up our logger, which logs everything from us in verbose mode
+    let mut logger = env_logger::Builder::from_default_env();
+    if options.verbose {
+        logger.filter_module("cloudwatcher", log::LevelFilter::Debug);
+    }
+    logger.init();
+
+    // See if we're talking to something other than AWS, such as LocalStack
+    let endpoint_url = options
+        .endpoint_url
+        .or_else(|| std::env::var("CLOUDWATCHER_ENDPOINT_URL").ok())
+        .filter(|endpoint_url| !endpoint_url.is_empty())
+        .map(|endpoint_url| match endpoint_url.parse::<Uri>() {
+            Ok(uri) if uri.scheme().is_some() && uri.authority().is_some() => uri,
+            Ok(_) => usage_error(format!(
+                "Invalid endpoint URL '{}': expected a URL such as http://localhost:4566",
+                endpoint_url
+            )),
+            Err(err) => usage_error(format!("Invalid endpoint URL '{}': {}", endpoint_url, err)),
+        });
+    match &endpoint_url {
+        Some(endpoint_url) => log::info!("Using custom endpoint: {}", endpoint_url),
+        None => log::info!("Using the default AWS endpoints"),
+    }
 
     // Establish our AWS configuration and create the CloudWatch client
-    let config = aws_config::from_env().region(region_provider).load().await;
-    let client = Client::new(&config);
+    let default_context = ClientContext {
+        profile,
+        role_arn: watch_set.role_arn.clone(),
+        region: region_provider
+            .region()
+            .await
+            .map(|region| region.to_string())
+            .unwrap_or_default(),
+        endpoint_url,
+    };
+    let sdk_config = default_context.load().await;
+    let client = Client::new(&sdk_config);
 
     // Parse the commands
     if let Some(command) = options.command {
         match command {
-            CloudWatcherCommands::List(_) => list_log_groups(&client).await,
+            CloudWatcherCommands::List(opts) => {
+                let CloudWatcherListOptions { prefix, limit, .. } = opts;
+                list_log_groups(&SdkLogSource::new(client), prefix, limit).await?;
+                Ok(())
+            }
             CloudWatcherCommands::Watch(opts) => {
+                let events = opts.event_options();
                 let CloudWatcherWatchOptions {
-                    groups, refresh, ..
+                    groups,
+                    refresh,
+                    since,
+                    from,
+                    lines,
+                    until,
+                    max_events,
+                    exit_on_match,
+                    summary,
+                    max_not_found,
+                    overlap,
+                    max_pages,
+                    max_poll_events,
+                    ..
                 } = opts;
+                let refresh = refresh.or_else(|| watch_set.refresh.clone());
 
-                if groups.is_empty() {
-                    println!("No log groups to watch");
-                    return Ok(());
+                // With --lines, the backlog covers everything before we start following
+                let since = match lines {
+                    Some(_) if since.is_some() || from.is_some() => {
+                        usage_error("Only one of --lines, --since and --from can be given")
+                    }
+                    Some(_) => Some("0".to_string()),
+                    None => since,
+                };
+
+                let start_time = match parse_start_time(
+                    since.as_deref(),
+                    from.as_deref(),
+                    Some(Duration::from_secs(600)),
+                    OffsetDateTime::now_utc(),
+                ) {
+                    Ok(start_time) => start_time,
+                    Err(err) => usage_error(err),
+                };
+
+                // The deadline is measured from when we start, rather than from the first event
+                let deadline =
+                    until.map(
+                        |until| match parse_until(&until, OffsetDateTime::now_utc()) {
+                            Ok(until) => Instant::now() + until,
+                            Err(err) => usage_error(err),
+                        },
+                    );
+
+                if max_events == Some(0) {
+                    usage_error("--max-events must be at least 1");
                 }
 
-                watch_log_groups(
-                    &client,
+                let exit_on_match = match exit_on_match.as_deref().map(Regex::new).transpose() {
+                    Ok(exit_on_match) => exit_on_match,
+                    Err(err) => usage_error(format!("Invalid --exit-on-match pattern: {}", err)),
+                };
+
+                let (groups, display, sources) = prepare_groups(
+                    groups,
+                    events,
+                    watch_set,
+                    &config,
+                    &default_context,
+                    sdk_config,
+                )
+                .await;
+
+                let default_budget = PollBudget::default();
+                let budget = PollBudget {
+                    max_pages: max_pages.unwrap_or(default_budget.max_pages).max(1),
+                    max_events: max_poll_events.unwrap_or(default_budget.max_events).max(1),
+                };
+
+                let refresh = match refresh.as_deref().map(parse_duration).transpose() {
+                    Ok(refresh) => refresh.unwrap_or_else(|| Duration::new(10, 0)),
+                    Err(err) => usage_error(format!("Invalid --refresh duration: {}", err)),
+                };
+                let overlap = match overlap.as_deref().map(parse_duration).transpose() {
+                    Ok(overlap) => overlap.unwrap_or(DEFAULT_POLL_OVERLAP),
+                    Err(err) => usage_error(format!("Invalid --overlap duration: {}", err)),
+                };
+                let watcher = Watcher::new(
+                    sources,
                     groups,
-                    refresh
-                        .map(|d| parse_duration(&d).expect("Failed to parse refresh duration"))
-                        .unwrap_or_else(|| Duration::new(10, 0)),
+                    WatcherSettings {
+                        refresh,
+                        budget,
+                        start_time,
+                        max_not_found: max_not_found.unwrap_or(3).max(1),
+                        overlap,
+                    },
+                );
+
+                let end = watch_log_groups(
+                    watcher,
+                    lines,
+                    &display,
+                    ExitConditions {
+                        deadline,
+                        max_events,
+                        exit_on_match,
+                    },
+                    shutdown,
+                    summary,
                 )
-                .await?;
+                .await;
+
+                std::process::exit(end.exit_code());
+            }
+            CloudWatcherCommands::Fetch(opts) => {
+                let events = opts.event_options();
+                let CloudWatcherFetchOptions {
+                    groups,
+                    since,
+                    from,
+                    to,
+                    ..
+                } = opts;
+
+                let (start_time, end_time) = match parse_time_range(
+                    since.as_deref(),
+                    from.as_deref(),
+                    to.as_deref(),
+                    None,
+                    OffsetDateTime::now_utc(),
+                ) {
+                    Ok(time_range) => time_range,
+                    Err(err) => usage_error(err),
+                };
+
+                let (groups, display, sources) = prepare_groups(
+                    groups,
+                    events,
+                    watch_set,
+                    &config,
+                    &default_context,
+                    sdk_config,
+                )
+                .await;
+
+                // The events we did get are still worth printing, but shouldn't pass for all of them
+                if !fetch_log_groups(&sources, &groups, start_time, end_time, &display).await {
+                    std::process::exit(1);
+                }
+                Ok(())
+            }
+            CloudWatcherCommands::Query(opts) => {
+                let CloudWatcherQueryOptions {
+                    query,
+                    groups,
+                    since,
+                    from,
+                    to,
+                    limit,
+                    output,
+                    ..
+                } = opts;
+
+                // Groups can be given as a comma-separated list, as well as repeated
+                let groups: Vec<String> = watch_set
+                    .groups
+                    .into_iter()
+                    .chain(groups.iter().flat_map(|groups| {
+                        groups
+                            .split(',')
+                            .map(str::trim)
+                            .filter(|group| !group.is_empty())
+                            .map(String::from)
+                            .collect::<Vec<_>>()
+                    }))
+                    .collect();
+                if groups.is_empty() {
+                    usage_error("No log groups to query");
+                }
+
+                let query = query.join(" ");
+                if query.trim().is_empty() {
+                    usage_error("No query given");
+                }
+
+                let (start_time, end_time) = match parse_time_range(
+                    since.as_deref(),
+                    from.as_deref(),
+                    to.as_deref(),
+                    Some(Duration::from_secs(3600)),
+                    OffsetDateTime::now_utc(),
+                ) {
+                    Ok(time_range) => time_range,
+                    Err(err) => usage_error(err),
+                };
+
+                let mut groups =
+                    resolve_groups(&groups, &default_context, &GroupFilters::default());
+                let sdk_configs =
+                    load_groups(&mut groups, &default_context, sdk_config, &config.accounts).await;
+
+                let succeeded = query_log_groups(
+                    &sdk_sources(&sdk_configs),
+                    &groups,
+                    GroupQuery {
+                        query,
+                        start_time: unix_millis(start_time),
+                        end_time: unix_millis(end_time),
+                        limit,
+                    },
+                    output.unwrap_or(QueryFormat::Table),
+                )
+                .await;
+
+                // The results we did get are still worth printing, but shouldn't pass for all of them
+                if !succeeded {
+                    std::process::exit(1);
+                }
                 Ok(())
             }
         }
     } else {
-        println!("No command given");
-        Ok(())
+        usage_error("No command given");
     }
 }
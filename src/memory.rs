@@ -0,0 +1,278 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+
+use crate::source::{
+    EventsPage, FilterRequest, LogGroupsPage, LogSource, QueryRequest, QueryResults, QueryStatus,
+    SourceError, SourceErrorKind, SourceEvent,
+};
+
+/// An in-memory [`LogSource`], for testing without AWS.
+///
+/// Filter patterns are treated as a plain substring match on the message. Clones share the same
+/// groups, so events can be added to a source after handing it to a watcher.
+#[derive(Clone)]
+pub struct MemoryLogSource {
+    state: Arc<Mutex<MemoryState>>,
+}
+
+struct MemoryState {
+    groups: BTreeMap<String, Vec<SourceEvent>>,
+    errors: HashMap<String, VecDeque<SourceError>>,
+    page_size: usize,
+    calls: usize,
+    next_event_id: usize,
+    /// The queries that have been started, indexed by their ID.
+    queries: Vec<QueryRequest>,
+    query_rows: Vec<Vec<(String, String)>>,
+    /// How many times a query reports that it is running before it completes.
+    query_polls: usize,
+    /// How many times we've been asked for the results of each query.
+    query_results: HashMap<String, usize>,
+}
+
+impl Default for MemoryLogSource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryLogSource {
+    pub fn new() -> Self {
+        Self::with_page_size(50)
+    }
+
+    /// Create a source that returns at most this many results in each page.
+    pub fn with_page_size(page_size: usize) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(MemoryState {
+                groups: BTreeMap::new(),
+                errors: HashMap::new(),
+                page_size,
+                calls: 0,
+                next_event_id: 0,
+                queries: Vec::new(),
+                query_rows: Vec::new(),
+                query_polls: 0,
+                query_results: HashMap::new(),
+            })),
+        }
+    }
+
+    pub fn add_group(&self, group: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .groups
+            .entry(group.to_string())
+            .or_default();
+    }
+
+    /// Add an event to a group (creating the group if need be), returning the event ID.
+    pub fn push_event(&self, group: &str, stream: &str, timestamp: i64, message: &str) -> String {
+        let mut state = self.state.lock().unwrap();
+        state.next_event_id += 1;
+        let event_id = format!("{:020}", state.next_event_id);
+        state
+            .groups
+            .entry(group.to_string())
+            .or_default()
+            .push(SourceEvent {
+                event_id: event_id.clone(),
+                stream: stream.to_string(),
+                timestamp,
+                ingestion_time: timestamp,
+                message: message.to_string(),
+            });
+        event_id
+    }
+
+    /// Make the next request for events from the group fail.
+    pub fn fail_next(&self, group: &str, kind: SourceErrorKind) {
+        self.state
+            .lock()
+            .unwrap()
+            .errors
+            .entry(group.to_string())
+            .or_default()
+            .push_back(SourceError::new(
+                kind,
+                format!("{:?} error for {}", kind, group),
+            ));
+    }
+
+    /// Set the rows that queries return, after reporting that they are running the given number of
+    /// times.
+    pub fn set_query_results(&self, rows: Vec<Vec<(String, String)>>, polls: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.query_rows = rows;
+        state.query_polls = polls;
+    }
+
+    /// The queries that have been started.
+    pub fn queries(&self) -> Vec<QueryRequest> {
+        self.state.lock().unwrap().queries.clone()
+    }
+
+    /// The number of requests that have been made of this source.
+    pub fn calls(&self) -> usize {
+        self.state.lock().unwrap().calls
+    }
+}
+
+/// Take a page of items starting at the offset given in the token.
+fn paginate<T: Clone>(
+    items: &[T],
+    next_token: Option<String>,
+    page_size: usize,
+) -> (Vec<T>, Option<String>) {
+    let start = next_token
+        .map(|token| token.parse::<usize>().expect("Invalid next token"))
+        .unwrap_or(0);
+    let end = (start + page_size).min(items.len());
+    let next_token = if end < items.len() {
+        Some(end.to_string())
+    } else {
+        None
+    };
+
+    (items[start.min(end)..end].to_vec(), next_token)
+}
+
+#[async_trait]
+impl LogSource for MemoryLogSource {
+    async fn describe_log_groups(
+        &self,
+        prefix: Option<String>,
+        limit: usize,
+        next_token: Option<String>,
+    ) -> Result<LogGroupsPage, SourceError> {
+        let mut state = self.state.lock().unwrap();
+        state.calls += 1;
+
+        let names = state
+            .groups
+            .keys()
+            .filter(|name| {
+                prefix
+                    .as_ref()
+                    .is_none_or(|prefix| name.starts_with(prefix))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        let (names, next_token) = paginate(&names, next_token, limit.min(state.page_size));
+        Ok(LogGroupsPage { names, next_token })
+    }
+
+    async fn filter_log_events(&self, request: FilterRequest) -> Result<EventsPage, SourceError> {
+        let mut state = self.state.lock().unwrap();
+        state.calls += 1;
+
+        if let Some(err) = state
+            .errors
+            .get_mut(&request.group)
+            .and_then(VecDeque::pop_front)
+        {
+            return Err(err);
+        }
+
+        let events = match state.groups.get(&request.group) {
+            Some(events) => events,
+            None => {
+                return Err(SourceError::new(
+                    SourceErrorKind::NotFound,
+                    "The specified log group does not exist.",
+                ))
+            }
+        };
+
+        let mut events = events
+            .iter()
+            .filter(|event| event.timestamp >= request.start_time)
+            .filter(|event| {
+                request
+                    .end_time
+                    .is_none_or(|end_time| event.timestamp <= end_time)
+            })
+            .filter(|event| {
+                request
+                    .filter_pattern
+                    .as_ref()
+                    .is_none_or(|pattern| event.message.contains(pattern.as_str()))
+            })
+            .filter(|event| {
+                request
+                    .stream_prefix
+                    .as_ref()
+                    .is_none_or(|prefix| event.stream.starts_with(prefix.as_str()))
+            })
+            .filter(|event| {
+                request.stream_names.is_empty() || request.stream_names.contains(&event.stream)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        events.sort_by_key(|event| event.timestamp);
+
+        let (events, next_token) = paginate(
+            &events,
+            request.next_token,
+            request.limit.min(state.page_size),
+        );
+        Ok(EventsPage { events, next_token })
+    }
+
+    async fn start_query(&self, request: QueryRequest) -> Result<String, SourceError> {
+        let mut state = self.state.lock().unwrap();
+        state.calls += 1;
+
+        if let Some(group) = request
+            .groups
+            .iter()
+            .find(|group| !state.groups.contains_key(*group))
+        {
+            return Err(SourceError::new(
+                SourceErrorKind::NotFound,
+                format!("Log group '{}' does not exist", group),
+            ));
+        }
+
+        state.queries.push(request);
+        Ok((state.queries.len() - 1).to_string())
+    }
+
+    async fn get_query_results(&self, query_id: &str) -> Result<QueryResults, SourceError> {
+        let mut state = self.state.lock().unwrap();
+        state.calls += 1;
+
+        let query = query_id
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| state.queries.get(index).cloned())
+            .ok_or_else(|| {
+                SourceError::new(SourceErrorKind::Other, format!("No query {}", query_id))
+            })?;
+
+        let polls = state.query_results.entry(query_id.to_string()).or_default();
+        *polls += 1;
+        let complete = *polls > state.query_polls;
+
+        let mut rows = state.query_rows.clone();
+        if let Some(limit) = query.limit {
+            rows.truncate(limit);
+        }
+
+        Ok(QueryResults {
+            status: if complete {
+                QueryStatus::Complete
+            } else {
+                QueryStatus::Running
+            },
+            records_matched: rows.len() as f64,
+            records_scanned: state.groups.values().map(Vec::len).sum::<usize>() as f64,
+            rows: if complete { rows } else { Vec::new() },
+        })
+    }
+}
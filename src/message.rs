@@ -0,0 +1,431 @@
+use std::str::FromStr;
+
+use console::Style;
+use serde_json::Value;
+
+/// How JSON found in messages is rendered in text output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JsonMode {
+    /// Indented over multiple lines.
+    Pretty,
+    /// On a single line, without any extra whitespace.
+    Compact,
+}
+
+impl FromStr for JsonMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(JsonMode::Pretty),
+            "compact" => Ok(JsonMode::Compact),
+            _ => Err(format!(
+                "unknown JSON mode '{}' (expected 'pretty' or 'compact')",
+                s
+            )),
+        }
+    }
+}
+
+/// Find a JSON object in the message, returning any text before it along with the parsed object.
+///
+/// The object can follow a text prefix, such as the `timestamp requestId level` that Lambda adds,
+/// so we try parsing from each `{` in turn. Trailing whitespace is ignored.
+pub fn find_json(message: &str) -> Option<(&str, Value)> {
+    let trimmed = message.trim_end();
+    if !trimmed.ends_with('}') {
+        return None;
+    }
+
+    trimmed.match_indices('{').find_map(|(index, _)| {
+        match serde_json::from_str(&trimmed[index..]) {
+            Ok(value @ Value::Object(_)) => Some((&message[..index], value)),
+            _ => None,
+        }
+    })
+}
+
+/// The styles used for the parts of JSON values.
+#[derive(Debug, Clone)]
+pub struct JsonStyles {
+    pub key: Style,
+    pub string: Style,
+    pub number: Style,
+    pub literal: Style,
+}
+
+impl Default for JsonStyles {
+    fn default() -> Self {
+        Self {
+            key: Style::new().blue(),
+            string: Style::new().green(),
+            number: Style::new().yellow(),
+            literal: Style::new().magenta(),
+        }
+    }
+}
+
+impl JsonStyles {
+    /// Render a JSON value with syntax highlighting.
+    pub fn render(&self, value: &Value, mode: JsonMode) -> String {
+        let mut out = String::new();
+        self.render_value(&mut out, value, mode, 0);
+        out
+    }
+
+    fn render_value(&self, out: &mut String, value: &Value, mode: JsonMode, depth: usize) {
+        match value {
+            Value::Null | Value::Bool(_) => out.push_str(&self.literal.apply_to(value).to_string()),
+            Value::Number(_) => out.push_str(&self.number.apply_to(value).to_string()),
+            Value::String(_) => out.push_str(&self.string.apply_to(value).to_string()),
+            Value::Array(items) => self.render_items(
+                out,
+                ('[', ']'),
+                items.iter().map(|item| (None, item)),
+                mode,
+                depth,
+            ),
+            Value::Object(fields) => self.render_items(
+                out,
+                ('{', '}'),
+                fields.iter().map(|(key, value)| (Some(key), value)),
+                mode,
+                depth,
+            ),
+        }
+    }
+
+    /// Render the items of an array, or the fields of an object when each item has a key.
+    fn render_items<'a>(
+        &self,
+        out: &mut String,
+        (open, close): (char, char),
+        items: impl ExactSizeIterator<Item = (Option<&'a String>, &'a Value)>,
+        mode: JsonMode,
+        depth: usize,
+    ) {
+        out.push(open);
+        if items.len() == 0 {
+            out.push(close);
+            return;
+        }
+
+        for (index, (key, value)) in items.enumerate() {
+            if index > 0 {
+                out.push(',');
+            }
+            if mode == JsonMode::Pretty {
+                out.push('\n');
+                out.push_str(&"  ".repeat(depth + 1));
+            }
+            if let Some(key) = key {
+                out.push_str(&self.key.apply_to(Value::from(key.as_str())).to_string());
+                out.push_str(if mode == JsonMode::Pretty { ": " } else { ":" });
+            }
+            self.render_value(out, value, mode, depth + 1);
+        }
+
+        if mode == JsonMode::Pretty {
+            out.push('\n');
+            out.push_str(&"  ".repeat(depth));
+        }
+        out.push(close);
+    }
+}
+
+/// Parse a logfmt message, such as `level=info msg="hello world" took=3ms`, into its fields.
+///
+/// Keys without a value are given an empty one. Returns `None` unless there is at least one
+/// `key=value` pair.
+pub fn parse_logfmt(message: &str) -> Option<Vec<(String, String)>> {
+    let mut fields = Vec::new();
+    let mut has_pair = false;
+    let mut chars = message.trim().chars().peekable();
+
+    while chars.peek().is_some() {
+        let mut key = String::new();
+        while let Some(&c) = chars.peek() {
+            if c == '=' || c.is_whitespace() {
+                break;
+            }
+            key.push(c);
+            chars.next();
+        }
+
+        let mut value = String::new();
+        if chars.peek() == Some(&'=') {
+            chars.next();
+            if key.is_empty() {
+                return None;
+            }
+            has_pair = true;
+
+            if chars.peek() == Some(&'"') {
+                chars.next();
+                let mut closed = false;
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => value.extend(chars.next()),
+                        '"' => {
+                            closed = true;
+                            break;
+                        }
+                        c => value.push(c),
+                    }
+                }
+                if !closed {
+                    return None;
+                }
+            } else {
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    value.push(c);
+                    chars.next();
+                }
+            }
+        }
+
+        if !key.is_empty() {
+            fields.push((key, value));
+        }
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+    }
+
+    if has_pair {
+        Some(fields)
+    } else {
+        None
+    }
+}
+
+/// Look up a dotted path, such as `request.headers.host` or `items.0.id`, in a JSON value.
+pub fn lookup_path<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    // Prefer a key that contains the dots, as some loggers flatten their fields
+    if let Some(value) = value.get(path) {
+        return Some(value);
+    }
+
+    path.split('.').try_fold(value, |value, part| match value {
+        Value::Object(fields) => fields.get(part),
+        Value::Array(items) => items.get(part.parse::<usize>().ok()?),
+        _ => None,
+    })
+}
+
+/// The fields of a structured message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fields {
+    Json(Value),
+    Logfmt(Vec<(String, String)>),
+}
+
+impl Fields {
+    /// Parse the fields of a JSON or logfmt message, or `None` if the message is not structured.
+    pub fn parse(message: &str) -> Option<Self> {
+        match find_json(message) {
+            Some((_, value)) => Some(Fields::Json(value)),
+            None => parse_logfmt(message).map(Fields::Logfmt),
+        }
+    }
+
+    /// Get a field by its dotted path. Logfmt values are always strings.
+    pub fn get(&self, path: &str) -> Option<Value> {
+        match self {
+            Fields::Json(value) => lookup_path(value, path).cloned(),
+            Fields::Logfmt(pairs) => pairs
+                .iter()
+                .find(|(key, _)| key == path)
+                .map(|(_, value)| Value::from(value.as_str())),
+        }
+    }
+}
+
+/// Format a field as `key=value`, quoting the value if it would otherwise be ambiguous.
+fn format_field(key: &str, value: &str) -> String {
+    if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"' || c == '=') {
+        format!("{}={}", key, Value::from(value))
+    } else {
+        format!("{}={}", key, value)
+    }
+}
+
+/// Selects a few fields from structured messages, given as dotted paths.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldProjection {
+    fields: Vec<String>,
+}
+
+impl FromStr for FieldProjection {
+    type Err = String;
+
+    /// Parse a comma-separated list of fields, such as `level,msg,request.id`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<String> = s
+            .split(',')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+            .map(String::from)
+            .collect();
+
+        if fields.is_empty() {
+            Err("expected a comma-separated list of fields".to_string())
+        } else {
+            Ok(Self { fields })
+        }
+    }
+}
+
+impl FieldProjection {
+    /// Render just the selected fields of a JSON or logfmt message as `key=value` pairs, in the
+    /// order they were asked for. Missing fields are left out. Returns `None` if the message is not
+    /// structured, or has none of the fields.
+    pub fn project(&self, message: &str) -> Option<String> {
+        let fields = Fields::parse(message)?;
+        let values: Vec<(&str, String)> = self
+            .fields
+            .iter()
+            .filter_map(|field| {
+                let value = match fields.get(field)? {
+                    Value::String(text) => text,
+                    other => other.to_string(),
+                };
+                Some((field.as_str(), value))
+            })
+            .collect();
+        if values.is_empty() {
+            return None;
+        }
+
+        Some(
+            values
+                .iter()
+                .map(|(key, value)| format_field(key, value))
+                .collect::<Vec<_>>()
+                .join(" "),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn plain() -> JsonStyles {
+        JsonStyles {
+            key: Style::new(),
+            string: Style::new(),
+            number: Style::new(),
+            literal: Style::new(),
+        }
+    }
+
+    #[test]
+    fn finds_json_messages() {
+        let (prefix, value) = find_json(r#"{"level":"info","msg":"hi"}"#).unwrap();
+        assert_eq!(prefix, "");
+        assert_eq!(value, json!({"level": "info", "msg": "hi"}));
+    }
+
+    #[test]
+    fn finds_json_after_a_prefix() {
+        let message = "2022-04-15T05:20:00.123Z\tabc-123\tINFO\t{\"msg\":\"{not here\",\"n\":1}\n";
+        let (prefix, value) = find_json(message).unwrap();
+        assert_eq!(prefix, "2022-04-15T05:20:00.123Z\tabc-123\tINFO\t");
+        assert_eq!(value, json!({"msg": "{not here", "n": 1}));
+    }
+
+    #[test]
+    fn ignores_other_messages() {
+        assert!(find_json("plain text").is_none());
+        assert!(find_json("broken {\"msg\": }").is_none());
+        assert!(find_json("[1, 2, 3]").is_none());
+        assert!(find_json("set {a, b}").is_none());
+    }
+
+    #[test]
+    fn parses_logfmt() {
+        let fields = parse_logfmt(r#"level=info msg="hello \"world\"" took=3ms debug"#).unwrap();
+        assert_eq!(
+            fields,
+            vec![
+                ("level".to_string(), "info".to_string()),
+                ("msg".to_string(), "hello \"world\"".to_string()),
+                ("took".to_string(), "3ms".to_string()),
+                ("debug".to_string(), String::new()),
+            ]
+        );
+
+        assert!(parse_logfmt("just some words").is_none());
+        assert!(parse_logfmt(r#"msg="unterminated"#).is_none());
+        assert!(parse_logfmt("=value").is_none());
+    }
+
+    #[test]
+    fn looks_up_dotted_paths() {
+        let value = json!({"a": {"b": [{"c": 1}]}, "x.y": 2});
+        assert_eq!(lookup_path(&value, "a.b.0.c"), Some(&json!(1)));
+        assert_eq!(lookup_path(&value, "x.y"), Some(&json!(2)));
+        assert_eq!(lookup_path(&value, "a.missing"), None);
+        assert_eq!(lookup_path(&value, "a.b.x"), None);
+    }
+
+    #[test]
+    fn projects_json_fields() {
+        let fields: FieldProjection = "level, msg,user.id,latency_ms,missing".parse().unwrap();
+        let message =
+            r#"INFO {"msg":"signed in","level":"info","user":{"id":42},"latency_ms":12.5}"#;
+        assert_eq!(
+            fields.project(message).unwrap(),
+            r#"level=info msg="signed in" user.id=42 latency_ms=12.5"#
+        );
+    }
+
+    #[test]
+    fn projects_logfmt_fields() {
+        let fields: FieldProjection = "level,msg,missing".parse().unwrap();
+        assert_eq!(
+            fields
+                .project(r#"ts=2022-04-15 level=warn msg="disk low""#)
+                .unwrap(),
+            r#"level=warn msg="disk low""#
+        );
+        assert_eq!(fields.project("plain text"), None);
+        assert_eq!(fields.project("ts=2022-04-15 status=200"), None);
+        assert!(" , ".parse::<FieldProjection>().is_err());
+    }
+
+    #[test]
+    fn renders_compact_json() {
+        let value = json!({"b": 1, "a": [true, null, "x"], "c": {}});
+        assert_eq!(
+            plain().render(&value, JsonMode::Compact),
+            r#"{"b":1,"a":[true,null,"x"],"c":{}}"#
+        );
+    }
+
+    #[test]
+    fn renders_pretty_json() {
+        let value = json!({"msg": "hi", "ctx": {"ids": [1, 2]}, "tags": []});
+        assert_eq!(
+            plain().render(&value, JsonMode::Pretty),
+            serde_json::to_string_pretty(&value).unwrap()
+        );
+    }
+
+    #[test]
+    fn highlights_json() {
+        let styles = JsonStyles {
+            key: Style::new().blue().force_styling(true),
+            ..plain()
+        };
+        assert_eq!(
+            styles.render(&json!({"a": 1}), JsonMode::Compact),
+            format!("{{{}:1}}", styles.key.apply_to("\"a\""))
+        );
+    }
+}
@@ -0,0 +1,629 @@
+use std::str::FromStr;
+
+use console::Style;
+use serde::Serialize;
+use time::{
+    format_description::{self, well_known::Rfc3339, FormatItem},
+    OffsetDateTime,
+};
+
+use crate::{
+    config::{ColorConfig, RuleConfig},
+    event::{unix_millis, LogEvent},
+    message::{find_json, FieldProjection, JsonMode, JsonStyles},
+    predicate::Predicate,
+    rules::{parse_rule_arg, Level, LevelFilter, Rules},
+};
+
+/// The format in which events are written to stdout.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// Colored text, one line per event.
+    Text,
+    /// One JSON object per line (see [`JsonEvent`]).
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!(
+                "unknown output format '{}' (expected 'text' or 'json')",
+                s
+            )),
+        }
+    }
+}
+
+/// The JSON representation of an event written by `--output json`.
+///
+/// Scripts depend on this format, so fields should only ever be added to it.
+#[derive(Serialize)]
+pub struct JsonEvent<'a> {
+    account: Option<&'a str>,
+    region: &'a str,
+    group: &'a str,
+    stream: &'a str,
+    event_id: &'a str,
+    /// The event timestamp in RFC 3339 format.
+    timestamp: String,
+    /// The event timestamp in milliseconds since the Unix epoch.
+    timestamp_millis: i64,
+    /// The time CloudWatch ingested the event, in RFC 3339 format.
+    ingestion_time: String,
+    /// The time CloudWatch ingested the event, in milliseconds since the Unix epoch.
+    ingestion_time_millis: i64,
+    message: &'a str,
+}
+
+impl<'a> From<&'a LogEvent> for JsonEvent<'a> {
+    fn from(event: &'a LogEvent) -> Self {
+        JsonEvent {
+            account: event.account.as_deref(),
+            region: &event.region,
+            group: &event.group,
+            stream: &event.stream,
+            event_id: &event.event_id,
+            timestamp: event
+                .timestamp
+                .format(&Rfc3339)
+                .expect("Failed to format timestamp"),
+            timestamp_millis: unix_millis(event.timestamp),
+            ingestion_time: event
+                .ingestion_time
+                .format(&Rfc3339)
+                .expect("Failed to format ingestion time"),
+            ingestion_time_millis: unix_millis(event.ingestion_time),
+            message: &event.message,
+        }
+    }
+}
+
+/// The styles used to render the prefix of events in text output. Messages are styled by
+/// [`Rules`].
+pub struct Colors {
+    pub timestamp: Style,
+    pub group: Style,
+    pub stream: Style,
+}
+
+impl Default for Colors {
+    fn default() -> Self {
+        Self {
+            timestamp: Style::new().green(),
+            group: Style::new().magenta(),
+            stream: Style::new().cyan(),
+        }
+    }
+}
+
+impl Colors {
+    /// Get the colors from the configuration, using the defaults for any not given.
+    pub fn from_config(config: &ColorConfig) -> Self {
+        fn style(value: &Option<String>, default: Style) -> Style {
+            value
+                .as_deref()
+                .map(Style::from_dotted_str)
+                .unwrap_or(default)
+        }
+
+        let defaults = Colors::default();
+        Self {
+            timestamp: style(&config.timestamp, defaults.timestamp),
+            group: style(&config.group, defaults.group),
+            stream: style(&config.stream, defaults.stream),
+        }
+    }
+}
+
+/// Renders events as lines of output.
+pub trait Formatter: Send + Sync {
+    /// Format the event as a single line, without a trailing newline.
+    fn format(&self, event: &LogEvent) -> String;
+}
+
+/// Writes each event as a line of JSON (see [`JsonEvent`]).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonFormatter;
+
+impl Formatter for JsonFormatter {
+    fn format(&self, event: &LogEvent) -> String {
+        serde_json::to_string(&JsonEvent::from(event)).expect("Failed to serialize event")
+    }
+}
+
+/// Writes each event as a line of colored text, prefixed with its timestamp and group.
+pub struct TextFormatter {
+    colors: Colors,
+    rules: Rules,
+    json: Option<JsonMode>,
+    json_styles: JsonStyles,
+    fields: Option<FieldProjection>,
+    show_stream: bool,
+    show_region: bool,
+    timestamp_format: Vec<FormatItem<'static>>,
+}
+
+impl TextFormatter {
+    pub fn new(colors: Colors, rules: Rules, show_stream: bool, show_region: bool) -> Self {
+        Self {
+            colors,
+            rules,
+            json: None,
+            json_styles: JsonStyles::default(),
+            fields: None,
+            show_stream,
+            show_region,
+            timestamp_format: format_description::parse(
+                "[year]-[month]-[day] [hour]:[minute]:[second]:[subsecond digits:6]",
+            )
+            .unwrap(),
+        }
+    }
+
+    /// Render any JSON found in messages, rather than showing it as it was logged.
+    pub fn with_json(mut self, json: JsonMode) -> Self {
+        self.json = Some(json);
+        self
+    }
+
+    /// Show only these fields of structured messages.
+    pub fn with_fields(mut self, fields: FieldProjection) -> Self {
+        self.fields = Some(fields);
+        self
+    }
+
+    fn format_message(&self, message: &str) -> String {
+        if let Some(json) = self.json {
+            if let Some((prefix, value)) = find_json(message) {
+                return format!(
+                    "{}{}",
+                    self.rules.render(prefix),
+                    self.json_styles.render(&value, json)
+                );
+            }
+        }
+
+        self.rules.render(message)
+    }
+
+    fn format_timestamp(&self, timestamp: OffsetDateTime) -> String {
+        timestamp.format(&self.timestamp_format).unwrap()
+    }
+}
+
+impl Formatter for TextFormatter {
+    fn format(&self, event: &LogEvent) -> String {
+        let timestamp = self.format_timestamp(event.timestamp);
+        let group = event.group_label(self.show_region);
+        let projected = self
+            .fields
+            .as_ref()
+            .and_then(|fields| fields.project(&event.message));
+        let message = self.format_message(projected.as_deref().unwrap_or(&event.message));
+
+        if self.show_stream {
+            format!(
+                "{} {} {}: {}",
+                self.colors.timestamp.apply_to(timestamp),
+                self.colors.group.apply_to(group),
+                self.colors.stream.apply_to(&event.stream),
+                message
+            )
+        } else {
+            format!(
+                "{} {}: {}",
+                self.colors.timestamp.apply_to(timestamp),
+                self.colors.group.apply_to(group),
+                message
+            )
+        }
+    }
+}
+
+/// The options that control which events are shown by `watch` and `fetch`, and how.
+#[derive(Debug, Clone, Default)]
+pub struct DisplayOptions {
+    /// Only show events at or above this level.
+    pub level: Option<Level>,
+    /// Only show structured events matching this `--where` expression.
+    pub where_: Option<String>,
+    pub show_stream: bool,
+    pub output: Option<OutputFormat>,
+    pub json: Option<JsonMode>,
+    pub fields: Option<FieldProjection>,
+    /// Rules given as `PATTERN=STYLE`, which style whole messages.
+    pub rule: Vec<String>,
+    /// Rules given as `PATTERN=STYLE`, which style only the text they match.
+    pub highlight: Vec<String>,
+}
+
+/// Decides which events are shown, and how.
+pub struct EventDisplay {
+    formatter: Box<dyn Formatter>,
+    rules: Rules,
+    level_filter: Option<LevelFilter>,
+    predicate: Option<Predicate>,
+}
+
+impl EventDisplay {
+    /// Set up the display from the options, with the rules and colors from the configuration, or
+    /// explain what's wrong with the options.
+    pub fn new(
+        options: DisplayOptions,
+        colors: &ColorConfig,
+        config_rules: impl IntoIterator<Item = RuleConfig>,
+        show_region: bool,
+    ) -> Result<Self, String> {
+        let DisplayOptions {
+            level,
+            where_,
+            show_stream,
+            output,
+            json,
+            fields,
+            rule,
+            highlight,
+        } = options;
+
+        // Rules from the command-line come before those from the configuration
+        let mut rule_configs = Vec::new();
+        for (arg, span) in rule
+            .iter()
+            .map(|arg| (arg, false))
+            .chain(highlight.iter().map(|arg| (arg, true)))
+        {
+            match parse_rule_arg(arg, span) {
+                Some(rule_config) => rule_configs.push(rule_config),
+                None => {
+                    return Err(format!(
+                        "Expected PATTERN=STYLE for --rule or --highlight: {}",
+                        arg
+                    ))
+                }
+            }
+        }
+        rule_configs.extend(config_rules);
+
+        let rules = Rules::from_config(&rule_configs, colors)
+            .map_err(|err| format!("Invalid rule: {}", err))?;
+
+        let level_filter = level.map(|level| LevelFilter::new(level, rules.clone()));
+
+        let predicate = where_
+            .as_deref()
+            .map(Predicate::from_str)
+            .transpose()
+            .map_err(|err| {
+                format!(
+                    "Invalid --where expression: {}\n  {}\n  {}^",
+                    err,
+                    where_.as_deref().unwrap_or_default(),
+                    " ".repeat(err.position)
+                )
+            })?;
+
+        // JSON output always has each message as it was logged
+        let output = output.unwrap_or(OutputFormat::Text);
+        if output == OutputFormat::Json {
+            if json.is_some() {
+                return Err("--json can only be used with text output".into());
+            }
+            if fields.is_some() {
+                return Err("--fields can only be used with text output".into());
+            }
+
+            // Colors would only corrupt machine-readable output
+            console::set_colors_enabled(false);
+        }
+
+        let formatter: Box<dyn Formatter> = match output {
+            OutputFormat::Text => {
+                let mut formatter = TextFormatter::new(
+                    Colors::from_config(colors),
+                    rules.clone(),
+                    show_stream,
+                    show_region,
+                );
+                if let Some(json) = json {
+                    formatter = formatter.with_json(json);
+                }
+                if let Some(fields) = fields {
+                    formatter = formatter.with_fields(fields);
+                }
+                Box::new(formatter)
+            }
+            OutputFormat::Json => Box::new(JsonFormatter),
+        };
+
+        Ok(Self {
+            formatter,
+            rules,
+            level_filter,
+            predicate,
+        })
+    }
+
+    /// Set up the display as [`EventDisplay::new`] does, but render events with `formatter`. The
+    /// options for the built-in formatters (`output`, `json`, `fields` and `show_stream`) are ignored.
+    pub fn with_formatter(
+        formatter: Box<dyn Formatter>,
+        options: DisplayOptions,
+        colors: &ColorConfig,
+        config_rules: impl IntoIterator<Item = RuleConfig>,
+    ) -> Result<Self, String> {
+        let options = DisplayOptions {
+            show_stream: false,
+            output: None,
+            json: None,
+            fields: None,
+            ..options
+        };
+        let display = Self::new(options, colors, config_rules, false)?;
+        Ok(Self {
+            formatter,
+            ..display
+        })
+    }
+
+    /// The level of the event's message, if it has one.
+    pub fn level(&self, event: &LogEvent) -> Option<Level> {
+        self.rules.detect_level(&event.message)
+    }
+
+    /// Whether the event passes the `--level` and `--where` filters.
+    pub fn matches(&self, event: &LogEvent) -> bool {
+        self.level_filter
+            .as_ref()
+            .is_none_or(|filter| filter.matches(&event.message))
+            && self
+                .predicate
+                .as_ref()
+                .is_none_or(|predicate| predicate.matches(&event.message))
+    }
+
+    /// Format the event for display, or return `None` if it's filtered out.
+    pub fn render(&self, event: LogEvent) -> Option<String> {
+        if !self.matches(&event) {
+            return None;
+        }
+
+        Some(self.formatter.format(&event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::from_unix_millis;
+
+    fn event() -> LogEvent {
+        LogEvent {
+            event_id: "1234".to_string(),
+            account: None,
+            region: "eu-west-1".to_string(),
+            group: "/ecs/service".to_string(),
+            stream: "web/abc".to_string(),
+            timestamp: from_unix_millis(1_650_000_000_123),
+            ingestion_time: from_unix_millis(1_650_000_001_000),
+            message: "ERROR something broke".to_string(),
+        }
+    }
+
+    fn text_formatter(show_stream: bool, show_region: bool) -> TextFormatter {
+        console::set_colors_enabled(false);
+        TextFormatter::new(
+            Colors::default(),
+            Rules::default(),
+            show_stream,
+            show_region,
+        )
+    }
+
+    #[test]
+    fn formats_text() {
+        let line = text_formatter(false, false).format(&event());
+        assert_eq!(
+            line,
+            "2022-04-15 05:20:00:123000 /ecs/service: ERROR something broke"
+        );
+    }
+
+    #[test]
+    fn formats_text_with_stream_and_region() {
+        let mut event = event();
+        event.account = Some("prod".to_string());
+        let line = text_formatter(true, true).format(&event);
+        assert_eq!(
+            line,
+            "2022-04-15 05:20:00:123000 prod@eu-west-1:/ecs/service web/abc: ERROR something broke"
+        );
+    }
+
+    #[test]
+    fn formats_json_messages() {
+        let mut event = event();
+        event.message = "INFO\t{\"msg\": \"hi\", \"n\": 1}".to_string();
+
+        let line = text_formatter(false, false)
+            .with_json(JsonMode::Compact)
+            .format(&event);
+        assert_eq!(
+            line,
+            "2022-04-15 05:20:00:123000 /ecs/service: INFO\t{\"msg\":\"hi\",\"n\":1}"
+        );
+
+        let line = text_formatter(false, false)
+            .with_json(JsonMode::Pretty)
+            .format(&event);
+        assert_eq!(
+            line,
+            "2022-04-15 05:20:00:123000 /ecs/service: INFO\t{\n  \"msg\": \"hi\",\n  \"n\": 1\n}"
+        );
+    }
+
+    #[test]
+    fn formats_json() {
+        let line = JsonFormatter.format(&event());
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["account"], serde_json::Value::Null);
+        assert_eq!(value["region"], "eu-west-1");
+        assert_eq!(value["group"], "/ecs/service");
+        assert_eq!(value["stream"], "web/abc");
+        assert_eq!(value["event_id"], "1234");
+        assert_eq!(value["timestamp"], "2022-04-15T05:20:00.123Z");
+        assert_eq!(value["timestamp_millis"], 1_650_000_000_123i64);
+        assert_eq!(value["ingestion_time_millis"], 1_650_000_001_000i64);
+        assert_eq!(value["message"], "ERROR something broke");
+    }
+
+    fn display(options: DisplayOptions) -> EventDisplay {
+        console::set_colors_enabled(false);
+        EventDisplay::new(options, &ColorConfig::default(), None, false).unwrap()
+    }
+
+    fn with_message(message: &str) -> LogEvent {
+        LogEvent {
+            message: message.to_string(),
+            ..event()
+        }
+    }
+
+    #[test]
+    fn filters_by_level() {
+        let display = display(DisplayOptions {
+            level: Some(Level::Warn),
+            ..DisplayOptions::default()
+        });
+        assert_eq!(display.render(with_message("INFO all good")), None);
+        assert_eq!(
+            display.render(with_message("ERROR something broke")),
+            Some("2022-04-15 05:20:00:123000 /ecs/service: ERROR something broke".to_string())
+        );
+        assert_eq!(
+            display.level(&with_message("{\"level\": \"warn\"}")),
+            Some(Level::Warn)
+        );
+    }
+
+    #[test]
+    fn filters_by_expression() {
+        let display = display(DisplayOptions {
+            where_: Some("status >= 500".to_string()),
+            ..DisplayOptions::default()
+        });
+        assert!(display.matches(&with_message("{\"status\": 503}")));
+        assert!(!display.matches(&with_message("{\"status\": 200}")));
+        assert!(!display.matches(&with_message("not structured")));
+    }
+
+    #[test]
+    fn projects_fields_of_shown_events() {
+        let display = display(DisplayOptions {
+            fields: Some("msg".parse().unwrap()),
+            ..DisplayOptions::default()
+        });
+        assert_eq!(
+            display.render(with_message("{\"msg\": \"hi\", \"n\": 1}")),
+            Some("2022-04-15 05:20:00:123000 /ecs/service: msg=hi".to_string())
+        );
+
+        // Messages without any of the fields are shown as they are
+        assert_eq!(
+            display.render(with_message("plain")),
+            Some("2022-04-15 05:20:00:123000 /ecs/service: plain".to_string())
+        );
+    }
+
+    #[test]
+    fn renders_with_any_formatter() {
+        struct MessageFormatter;
+
+        impl Formatter for MessageFormatter {
+            fn format(&self, event: &LogEvent) -> String {
+                format!("[{}] {}", event.stream, event.message)
+            }
+        }
+
+        let display = EventDisplay::with_formatter(
+            Box::new(MessageFormatter),
+            DisplayOptions {
+                level: Some(Level::Warn),
+                ..DisplayOptions::default()
+            },
+            &ColorConfig::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(display.render(with_message("INFO all good")), None);
+        assert_eq!(
+            display.render(with_message("ERROR something broke")),
+            Some("[web/abc] ERROR something broke".to_string())
+        );
+    }
+
+    #[test]
+    fn explains_invalid_options() {
+        let rule = EventDisplay::new(
+            DisplayOptions {
+                rule: vec!["no style".to_string()],
+                ..DisplayOptions::default()
+            },
+            &ColorConfig::default(),
+            None,
+            false,
+        );
+        assert_eq!(
+            rule.err(),
+            Some("Expected PATTERN=STYLE for --rule or --highlight: no style".to_string())
+        );
+
+        let where_ = EventDisplay::new(
+            DisplayOptions {
+                where_: Some("status >=".to_string()),
+                ..DisplayOptions::default()
+            },
+            &ColorConfig::default(),
+            None,
+            false,
+        );
+        assert!(where_
+            .err()
+            .unwrap()
+            .starts_with("Invalid --where expression: "));
+
+        let json = EventDisplay::new(
+            DisplayOptions {
+                output: Some(OutputFormat::Json),
+                json: Some(JsonMode::Pretty),
+                ..DisplayOptions::default()
+            },
+            &ColorConfig::default(),
+            None,
+            false,
+        );
+        assert_eq!(
+            json.err(),
+            Some("--json can only be used with text output".to_string())
+        );
+
+        let fields = EventDisplay::new(
+            DisplayOptions {
+                output: Some(OutputFormat::Json),
+                fields: Some("msg".parse().unwrap()),
+                ..DisplayOptions::default()
+            },
+            &ColorConfig::default(),
+            None,
+            false,
+        );
+        assert_eq!(
+            fields.err(),
+            Some("--fields can only be used with text output".to_string())
+        );
+    }
+}
@@ -0,0 +1,567 @@
+use std::{cmp::Ordering, fmt::Display, str::FromStr};
+
+use regex::Regex;
+use serde_json::Value;
+
+use crate::message::Fields;
+
+/// A condition on the fields of structured (JSON or logfmt) messages, given with `--where`.
+///
+/// ```text
+/// status >= 500 && path ~ "^/api"
+/// !(level == "debug" || user.internal == true)
+/// request_id && latency_ms > 250
+/// ```
+///
+/// Fields are named by dotted paths. A field on its own is true if the message has it. The
+/// comparisons are `==`, `!=`, `<`, `<=`, `>` and `>=`, along with `~` and `!~` to match a regular
+/// expression. Comparing a field that is missing is always false.
+#[derive(Debug, Clone)]
+pub struct Predicate {
+    expr: Expr,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Exists(String),
+    Compare(String, CompareOp, Literal),
+    Matches(String, Regex),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Literal {
+    String(String),
+    Number(f64),
+    Bool(bool),
+    Null,
+}
+
+/// An error in a `--where` expression, with the position (in characters) at which it was found.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PredicateError {
+    pub message: String,
+    pub position: usize,
+}
+
+impl Display for PredicateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position + 1)
+    }
+}
+
+impl std::error::Error for PredicateError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Field(String),
+    String(String),
+    Number(f64),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+    Compare(CompareOp),
+    Match,
+    NotMatch,
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Field(field) => write!(f, "'{}'", field),
+            Token::String(string) => write!(f, "{:?}", string),
+            Token::Number(number) => write!(f, "{}", number),
+            Token::And => f.write_str("'&&'"),
+            Token::Or => f.write_str("'||'"),
+            Token::Not => f.write_str("'!'"),
+            Token::Open => f.write_str("'('"),
+            Token::Close => f.write_str("')'"),
+            Token::Compare(op) => f.write_str(match op {
+                CompareOp::Eq => "'=='",
+                CompareOp::Ne => "'!='",
+                CompareOp::Lt => "'<'",
+                CompareOp::Le => "'<='",
+                CompareOp::Gt => "'>'",
+                CompareOp::Ge => "'>='",
+            }),
+            Token::Match => f.write_str("'~'"),
+            Token::NotMatch => f.write_str("'!~'"),
+        }
+    }
+}
+
+fn error(message: impl Into<String>, position: usize) -> PredicateError {
+    PredicateError {
+        message: message.into(),
+        position,
+    }
+}
+
+/// Split the expression into tokens, along with the position at which each starts.
+fn tokenize(input: &str) -> Result<Vec<(Token, usize)>, PredicateError> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut index = 0;
+
+    while index < chars.len() {
+        let start = index;
+        let c = chars[index];
+        let next = chars.get(index + 1).copied();
+
+        let token = match (c, next) {
+            _ if c.is_whitespace() => {
+                index += 1;
+                continue;
+            }
+            ('&', Some('&')) => Token::And,
+            ('|', Some('|')) => Token::Or,
+            ('=', Some('=')) => Token::Compare(CompareOp::Eq),
+            ('!', Some('=')) => Token::Compare(CompareOp::Ne),
+            ('<', Some('=')) => Token::Compare(CompareOp::Le),
+            ('>', Some('=')) => Token::Compare(CompareOp::Ge),
+            ('!', Some('~')) => Token::NotMatch,
+            ('<', _) => Token::Compare(CompareOp::Lt),
+            ('>', _) => Token::Compare(CompareOp::Gt),
+            ('~', _) => Token::Match,
+            ('!', _) => Token::Not,
+            ('(', _) => Token::Open,
+            (')', _) => Token::Close,
+            ('"' | '\'', _) => {
+                let mut string = String::new();
+                index += 1;
+                loop {
+                    match chars.get(index) {
+                        None => return Err(error("unterminated string", start)),
+                        // Only quotes and backslashes are escaped, so that regexes keep theirs
+                        Some('\\') => match chars.get(index + 1) {
+                            Some(&escaped @ ('"' | '\'' | '\\')) => {
+                                string.push(escaped);
+                                index += 2;
+                            }
+                            _ => {
+                                string.push('\\');
+                                index += 1;
+                            }
+                        },
+                        Some(&quote) if quote == c => break,
+                        Some(&other) => {
+                            string.push(other);
+                            index += 1;
+                        }
+                    }
+                }
+                index += 1;
+                tokens.push((Token::String(string), start));
+                continue;
+            }
+            _ if c.is_ascii_digit() || (c == '-' && next.is_some_and(|n| n.is_ascii_digit())) => {
+                index += 1;
+                while chars
+                    .get(index)
+                    .is_some_and(|c| c.is_ascii_alphanumeric() || *c == '.' || *c == '-')
+                {
+                    index += 1;
+                }
+                let text: String = chars[start..index].iter().collect();
+                let number = text
+                    .parse()
+                    .map_err(|_| error(format!("invalid number '{}'", text), start))?;
+                tokens.push((Token::Number(number), start));
+                continue;
+            }
+            _ if is_field_char(c) => {
+                while chars.get(index).is_some_and(|c| is_field_char(*c)) {
+                    index += 1;
+                }
+                let field: String = chars[start..index].iter().collect();
+                tokens.push((Token::Field(field), start));
+                continue;
+            }
+            _ => return Err(error(format!("unexpected character '{}'", c), start)),
+        };
+
+        index += match token {
+            Token::And
+            | Token::Or
+            | Token::NotMatch
+            | Token::Compare(CompareOp::Eq | CompareOp::Ne | CompareOp::Le | CompareOp::Ge) => 2,
+            _ => 1,
+        };
+        tokens.push((token, start));
+    }
+
+    Ok(tokens)
+}
+
+fn is_field_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '.' | '-' | '@' | '$')
+}
+
+/// A recursive descent parser over the tokens, where `||` binds more loosely than `&&`, which
+/// binds more loosely than `!`.
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    index: usize,
+    /// The position just past the end of the input, for errors at the end.
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index).map(|(token, _)| token)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens
+            .get(self.index)
+            .map_or(self.end, |(_, position)| *position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.index).map(|(token, _)| token.clone());
+        self.index += 1;
+        token
+    }
+
+    fn unexpected(&self, expected: &str) -> PredicateError {
+        match self.peek() {
+            Some(token) => error(
+                format!("expected {}, found {}", expected, token),
+                self.position(),
+            ),
+            None => error(format!("expected {}", expected), self.position()),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, PredicateError> {
+        let mut expr = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, PredicateError> {
+        let mut expr = self.parse_unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, PredicateError> {
+        match self.peek() {
+            Some(Token::Not) => {
+                self.next();
+                Ok(Expr::Not(Box::new(self.parse_unary()?)))
+            }
+            Some(Token::Open) => {
+                self.next();
+                let expr = self.parse_or()?;
+                if self.peek() != Some(&Token::Close) {
+                    return Err(self.unexpected("')'"));
+                }
+                self.next();
+                Ok(expr)
+            }
+            Some(Token::Field(_)) => self.parse_condition(),
+            _ => Err(self.unexpected("a field, '!' or '('")),
+        }
+    }
+
+    fn parse_condition(&mut self) -> Result<Expr, PredicateError> {
+        let field = match self.next() {
+            Some(Token::Field(field)) => field,
+            _ => unreachable!("conditions start with a field"),
+        };
+
+        match self.peek() {
+            Some(Token::Compare(op)) => {
+                let op = *op;
+                self.next();
+                let literal = self.parse_literal()?;
+                Ok(Expr::Compare(field, op, literal))
+            }
+            Some(Token::Match | Token::NotMatch) => {
+                let negate = self.next() == Some(Token::NotMatch);
+                let position = self.position();
+                let pattern = match self.next() {
+                    Some(Token::String(pattern)) => pattern,
+                    _ => {
+                        self.index -= 1;
+                        return Err(self.unexpected("a quoted regular expression"));
+                    }
+                };
+                let regex = Regex::new(&pattern).map_err(|err| {
+                    // Syntax errors span several lines to point at the problem, the last of which
+                    // says what it is
+                    let err = err.to_string();
+                    let reason = err.lines().last().unwrap_or_default();
+                    error(
+                        format!(
+                            "invalid regular expression: {}",
+                            reason.trim_start_matches("error: ")
+                        ),
+                        position,
+                    )
+                })?;
+
+                let expr = Expr::Matches(field, regex);
+                Ok(if negate {
+                    Expr::Not(Box::new(expr))
+                } else {
+                    expr
+                })
+            }
+            _ => Ok(Expr::Exists(field)),
+        }
+    }
+
+    fn parse_literal(&mut self) -> Result<Literal, PredicateError> {
+        let literal = match self.peek() {
+            Some(Token::String(string)) => Literal::String(string.clone()),
+            Some(Token::Number(number)) => Literal::Number(*number),
+            Some(Token::Field(word)) if word == "true" => Literal::Bool(true),
+            Some(Token::Field(word)) if word == "false" => Literal::Bool(false),
+            Some(Token::Field(word)) if word == "null" => Literal::Null,
+            _ => return Err(self.unexpected("a string, number, true, false or null")),
+        };
+
+        self.next();
+        Ok(literal)
+    }
+}
+
+impl FromStr for Predicate {
+    type Err = PredicateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            index: 0,
+            end: s.chars().count(),
+        };
+
+        let expr = parser.parse_or()?;
+        if parser.peek().is_some() {
+            return Err(parser.unexpected("'&&', '||' or the end of the expression"));
+        }
+
+        Ok(Self { expr })
+    }
+}
+
+/// Get a number from a field, parsing strings as logfmt values are always strings.
+fn as_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(string) => string.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Get the text of a field, without quotes for strings.
+fn as_text(value: &Value) -> String {
+    match value {
+        Value::String(string) => string.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Compare a field to a literal, or `None` if they can't be compared.
+fn compare(value: &Value, literal: &Literal) -> Option<Ordering> {
+    match literal {
+        Literal::Number(number) => as_number(value)?.partial_cmp(number),
+        Literal::String(string) => Some(as_text(value).as_str().cmp(string.as_str())),
+        Literal::Bool(expected) => match value {
+            Value::Bool(actual) => Some(actual.cmp(expected)),
+            Value::String(actual) => Some(actual.as_str().cmp(&expected.to_string())),
+            _ => None,
+        },
+        Literal::Null => match value {
+            Value::Null => Some(Ordering::Equal),
+            _ => None,
+        },
+    }
+}
+
+impl Expr {
+    fn eval(&self, fields: Option<&Fields>) -> bool {
+        let get = |field: &str| fields.and_then(|fields| fields.get(field));
+
+        match self {
+            Expr::And(left, right) => left.eval(fields) && right.eval(fields),
+            Expr::Or(left, right) => left.eval(fields) || right.eval(fields),
+            Expr::Not(expr) => !expr.eval(fields),
+            Expr::Exists(field) => get(field).is_some_and(|value| !value.is_null()),
+            Expr::Matches(field, regex) => {
+                get(field).is_some_and(|value| regex.is_match(&as_text(&value)))
+            }
+            Expr::Compare(field, op, literal) => {
+                let ordering = match get(field).and_then(|value| compare(&value, literal)) {
+                    Some(ordering) => ordering,
+                    None => return false,
+                };
+
+                match op {
+                    CompareOp::Eq => ordering == Ordering::Equal,
+                    CompareOp::Ne => ordering != Ordering::Equal,
+                    CompareOp::Lt => ordering == Ordering::Less,
+                    CompareOp::Le => ordering != Ordering::Greater,
+                    CompareOp::Gt => ordering == Ordering::Greater,
+                    CompareOp::Ge => ordering != Ordering::Less,
+                }
+            }
+        }
+    }
+}
+
+impl Predicate {
+    /// Whether the fields of the message satisfy the predicate. Messages that are not structured
+    /// have no fields.
+    pub fn matches(&self, message: &str) -> bool {
+        self.expr.eval(Fields::parse(message).as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(predicate: &str, message: &str) -> bool {
+        predicate.parse::<Predicate>().unwrap().matches(message)
+    }
+
+    fn parse_error(predicate: &str) -> PredicateError {
+        predicate.parse::<Predicate>().unwrap_err()
+    }
+
+    const REQUEST: &str =
+        r#"{"status":503,"path":"/api/users","user":{"id":"u-1","admin":false},"tags":null}"#;
+
+    #[test]
+    fn compares_numbers() {
+        assert!(matches("status >= 500", REQUEST));
+        assert!(matches("status == 503", REQUEST));
+        assert!(!matches("status < 500", REQUEST));
+        assert!(matches("status != 200", REQUEST));
+        assert!(matches("status > -1.5", REQUEST));
+    }
+
+    #[test]
+    fn compares_strings_and_literals() {
+        assert!(matches(r#"user.id == "u-1""#, REQUEST));
+        assert!(matches("user.id == 'u-1'", REQUEST));
+        assert!(matches("user.admin == false", REQUEST));
+        assert!(matches("tags == null", REQUEST));
+        assert!(!matches(r#"user.id != "u-1""#, REQUEST));
+    }
+
+    #[test]
+    fn matches_regular_expressions() {
+        assert!(matches(r#"path ~ "^/api""#, REQUEST));
+        assert!(!matches(r#"path !~ "^/api""#, REQUEST));
+        assert!(matches(r#"status ~ "^5""#, REQUEST));
+    }
+
+    #[test]
+    fn keeps_regex_escapes() {
+        assert!(matches(r#"path ~ "^/api/v\d+$""#, r#"{"path":"/api/v2"}"#));
+        assert!(!matches(
+            r#"path ~ "^/api/v\d+$""#,
+            r#"{"path":"/api/vdd"}"#
+        ));
+        assert!(matches(
+            r#"host ~ "\.example\.com$""#,
+            r#"{"host":"a.example.com"}"#
+        ));
+        assert!(!matches(
+            r#"host ~ "\.example\.com$""#,
+            r#"{"host":"a-example.com"}"#
+        ));
+    }
+
+    #[test]
+    fn unescapes_quotes_and_backslashes() {
+        let message = r#"{"msg":"say \"hi\"","path":"C:\\logs","name":"o'brien"}"#;
+        assert!(matches(r#"msg == "say \"hi\"""#, message));
+        assert!(matches(r#"path == "C:\\logs""#, message));
+        assert!(matches(r#"name == 'o\'brien'"#, message));
+    }
+
+    #[test]
+    fn checks_existence() {
+        assert!(matches("user.id", REQUEST));
+        assert!(!matches("missing", REQUEST));
+        assert!(!matches("tags", REQUEST));
+        assert!(matches("!missing", REQUEST));
+    }
+
+    #[test]
+    fn combines_conditions() {
+        assert!(matches(r#"status >= 500 && path ~ "^/api""#, REQUEST));
+        assert!(matches("status < 500 || user.admin == false", REQUEST));
+        assert!(!matches("!(status >= 500 || missing)", REQUEST));
+
+        // && binds more tightly than ||
+        assert!(matches("status == 503 || missing && missing", REQUEST));
+        assert!(!matches("(status == 503 || missing) && missing", REQUEST));
+    }
+
+    #[test]
+    fn missing_fields_never_compare() {
+        assert!(!matches("missing == 1", REQUEST));
+        assert!(!matches("missing != 1", REQUEST));
+        assert!(!matches("status == 503", "request failed with status 503"));
+    }
+
+    #[test]
+    fn reads_logfmt_fields() {
+        let message = r#"level=warn status=502 path="/api/orders""#;
+        assert!(matches(r#"status >= 500 && path ~ "^/api""#, message));
+        assert!(matches(r#"level == "warn""#, message));
+    }
+
+    #[test]
+    fn reports_parse_errors() {
+        assert_eq!(
+            parse_error("status >="),
+            PredicateError {
+                message: "expected a string, number, true, false or null".to_string(),
+                position: 9,
+            }
+        );
+        assert_eq!(
+            parse_error("status >= 500 path").to_string(),
+            "expected '&&', '||' or the end of the expression, found 'path' at position 15"
+        );
+        assert_eq!(parse_error(r#"path ~ "(""#).position, 7);
+        assert_eq!(
+            parse_error("path ~ foo").message,
+            "expected a quoted regular expression, found 'foo'"
+        );
+        assert_eq!(
+            parse_error(r#"path == "open"#).message,
+            "unterminated string"
+        );
+        assert_eq!(parse_error("(status").message, "expected ')'");
+        assert_eq!(parse_error("a = 1").message, "unexpected character '='");
+        assert_eq!(parse_error("").message, "expected a field, '!' or '('");
+    }
+}
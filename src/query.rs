@@ -0,0 +1,505 @@
+use std::{cell::RefCell, collections::HashMap, str::FromStr, time::Duration};
+
+use futures::future::join_all;
+use serde_json::{Map, Value};
+
+use crate::{
+    context::ClientContext,
+    source::{LogSource, QueryRequest, QueryResults, QueryStatus, SourceError, SourceErrorKind},
+    watch::WatchGroup,
+};
+
+/// How often we check on a running query.
+pub const QUERY_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A Logs Insights query to run against groups that may be spread across accounts and regions (see
+/// [`query_groups`]).
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupQuery {
+    /// The Logs Insights query.
+    pub query: String,
+    /// The start of the time range (in milliseconds) to query.
+    pub start_time: i64,
+    /// The end of the time range (in milliseconds) to query.
+    pub end_time: i64,
+    /// The most results to return from each account and region.
+    pub limit: Option<usize>,
+}
+
+impl GroupQuery {
+    /// The request to run this query against the named groups.
+    fn request(&self, groups: Vec<String>) -> QueryRequest {
+        QueryRequest {
+            groups,
+            query: self.query.clone(),
+            start_time: self.start_time,
+            end_time: self.end_time,
+            limit: self.limit,
+        }
+    }
+}
+
+/// Start a Logs Insights query and wait for it to finish, calling `progress` with the results so
+/// far each time we check on it.
+pub async fn run_query<S: LogSource + ?Sized>(
+    source: &S,
+    request: QueryRequest,
+    poll_interval: Duration,
+    mut progress: impl FnMut(&QueryResults),
+) -> Result<QueryResults, SourceError> {
+    let query_id = source.start_query(request).await?;
+    log::debug!("Started query {}", query_id);
+
+    loop {
+        let results = source.get_query_results(&query_id).await?;
+        match results.status {
+            QueryStatus::Complete => return Ok(results),
+            status if status.is_finished() => {
+                return Err(SourceError::new(
+                    SourceErrorKind::Other,
+                    format!("Query {} did not complete ({:?})", query_id, status),
+                ))
+            }
+            _ => progress(&results),
+        }
+
+        tokio::time::sleep(poll_interval).await;
+    }
+}
+
+/// Run the query against the groups, once for each combination of credentials and region, and
+/// gather the rows into one table. `progress` is called with the records matched and scanned so far
+/// across all of the queries.
+///
+/// When there's more than one query, the rows are labelled with where they came from in an
+/// `@source` column. A query that fails doesn't stop the others; its error is returned alongside
+/// the table, with the same label.
+pub async fn query_groups<S: LogSource>(
+    sources: &HashMap<ClientContext, S>,
+    groups: &[WatchGroup],
+    query: &GroupQuery,
+    poll_interval: Duration,
+    progress: impl Fn(f64, f64),
+) -> (QueryTable, Vec<(String, SourceError)>) {
+    // Each query can only cover the groups of one account and region
+    let mut queries: Vec<(&ClientContext, Vec<&WatchGroup>)> = Vec::new();
+    for group in groups {
+        match queries
+            .iter_mut()
+            .find(|(context, _)| **context == group.context)
+        {
+            Some((_, groups)) => groups.push(group),
+            None => queries.push((&group.context, vec![group])),
+        }
+    }
+
+    let records = RefCell::new(vec![(0.0, 0.0); queries.len()]);
+    let report = |index: usize, results: &QueryResults| {
+        let (matched, scanned) = {
+            let mut records = records.borrow_mut();
+            records[index] = (results.records_matched, results.records_scanned);
+            records
+                .iter()
+                .fold((0.0, 0.0), |(m, s), (matched, scanned)| {
+                    (m + matched, s + scanned)
+                })
+        };
+        progress(matched, scanned);
+    };
+
+    let results = join_all(
+        queries
+            .iter()
+            .enumerate()
+            .map(|(index, (context, groups))| {
+                let request =
+                    query.request(groups.iter().map(|group| group.name.clone()).collect());
+                let report = &report;
+                run_query(&sources[*context], request, poll_interval, move |results| {
+                    report(index, results)
+                })
+            }),
+    )
+    .await;
+
+    let mut table = QueryTable::default();
+    let mut errors = Vec::new();
+    for ((_, groups), result) in queries.iter().zip(results) {
+        let source = match &groups[0].account {
+            Some(account) => format!("{}@{}", account, groups[0].context.region),
+            None => groups[0].context.region.clone(),
+        };
+
+        match result {
+            Ok(results) => {
+                let start = table.rows.len();
+                table.extend(&results.rows);
+                if queries.len() > 1 {
+                    table.label_rows("@source", &source, start..table.rows.len());
+                }
+            }
+            Err(err) => errors.push((source, err)),
+        }
+    }
+
+    (table, errors)
+}
+
+/// The format in which query results are written to stdout.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueryFormat {
+    /// A table with aligned columns.
+    Table,
+    /// One JSON object per row.
+    Json,
+    /// Comma-separated values, with a header row.
+    Csv,
+}
+
+impl FromStr for QueryFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(QueryFormat::Table),
+            "json" => Ok(QueryFormat::Json),
+            "csv" => Ok(QueryFormat::Csv),
+            _ => Err(format!(
+                "unknown output format '{}' (expected 'table', 'json' or 'csv')",
+                s
+            )),
+        }
+    }
+}
+
+/// The rows of one or more queries, arranged into columns.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueryTable {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+impl QueryTable {
+    /// Add rows of query results, adding any new fields as columns. The `@ptr` field that Logs
+    /// Insights adds to each row is left out.
+    pub fn extend(&mut self, rows: &[Vec<(String, String)>]) {
+        for row in rows {
+            let mut values = vec![String::new(); self.columns.len()];
+            for (field, value) in row {
+                if field == "@ptr" {
+                    continue;
+                }
+
+                let index = match self.columns.iter().position(|column| column == field) {
+                    Some(index) => index,
+                    None => {
+                        self.columns.push(field.clone());
+                        values.push(String::new());
+                        self.columns.len() - 1
+                    }
+                };
+                values[index] = value.clone();
+            }
+
+            self.rows.push(values);
+        }
+
+        // Rows from before a column was added don't have a value for it
+        let width = self.columns.len();
+        for row in &mut self.rows {
+            row.resize(width, String::new());
+        }
+    }
+
+    /// Add a column at the start of the table, with the same value for the given rows.
+    pub fn label_rows(&mut self, column: &str, label: &str, rows: std::ops::Range<usize>) {
+        if self.columns.first().map(String::as_str) != Some(column) {
+            self.columns.insert(0, column.to_string());
+            for row in &mut self.rows {
+                row.insert(0, String::new());
+            }
+        }
+
+        for row in &mut self.rows[rows] {
+            row[0] = label.to_string();
+        }
+    }
+
+    pub fn render(&self, format: QueryFormat) -> String {
+        match format {
+            QueryFormat::Table => self.to_table(),
+            QueryFormat::Json => self.to_json(),
+            QueryFormat::Csv => self.to_csv(),
+        }
+    }
+
+    /// Render the rows as a table, with each column as wide as its widest value.
+    fn to_table(&self) -> String {
+        let widths: Vec<usize> = self
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(index, column)| {
+                self.rows
+                    .iter()
+                    .map(|row| row[index].chars().count())
+                    .chain(Some(column.chars().count()))
+                    .max()
+                    .unwrap_or_default()
+            })
+            .collect();
+
+        let line = |values: &[String]| {
+            values
+                .iter()
+                .zip(&widths)
+                .map(|(value, width)| format!("{:width$}", value, width = width))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        };
+
+        let mut lines = vec![line(&self.columns)];
+        lines.push(
+            widths
+                .iter()
+                .map(|width| "-".repeat(*width))
+                .collect::<Vec<_>>()
+                .join("  "),
+        );
+        lines.extend(self.rows.iter().map(|row| line(row)));
+        lines.join("\n")
+    }
+
+    /// Render each row as a JSON object on its own line.
+    fn to_json(&self) -> String {
+        self.rows
+            .iter()
+            .map(|row| {
+                let object: Map<String, Value> = self
+                    .columns
+                    .iter()
+                    .zip(row)
+                    .filter(|(_, value)| !value.is_empty())
+                    .map(|(column, value)| (column.clone(), Value::from(value.as_str())))
+                    .collect();
+                Value::Object(object).to_string()
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Render the rows as CSV, quoting values as described in RFC 4180.
+    fn to_csv(&self) -> String {
+        fn field(value: &str) -> String {
+            if value.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", value.replace('"', "\"\""))
+            } else {
+                value.to_string()
+            }
+        }
+
+        let line = |values: &[String]| {
+            values
+                .iter()
+                .map(|value| field(value))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+
+        Some(line(&self.columns))
+            .into_iter()
+            .chain(self.rows.iter().map(|row| line(row)))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{memory::MemoryLogSource, test_support};
+
+    fn row(fields: &[(&str, &str)]) -> Vec<(String, String)> {
+        fields
+            .iter()
+            .map(|(field, value)| (field.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn table() -> QueryTable {
+        let mut table = QueryTable::default();
+        table.extend(&[
+            row(&[
+                ("@timestamp", "2022-04-15 05:20:00.123"),
+                ("@message", "hello, world"),
+                ("@ptr", "x"),
+            ]),
+            row(&[("@timestamp", "2022-04-15 05:20:01.000"), ("status", "500")]),
+        ]);
+        table
+    }
+
+    fn query() -> GroupQuery {
+        GroupQuery {
+            query: "fields @timestamp, @message".to_string(),
+            start_time: 0,
+            end_time: 1000,
+            limit: Some(1),
+        }
+    }
+
+    fn request(groups: &[&str]) -> QueryRequest {
+        query().request(groups.iter().map(|group| group.to_string()).collect())
+    }
+
+    #[tokio::test]
+    async fn polls_until_complete() {
+        let source = MemoryLogSource::new();
+        source.add_group("a");
+        source.set_query_results(
+            vec![row(&[("@message", "one")]), row(&[("@message", "two")])],
+            2,
+        );
+
+        let mut polls = 0;
+        let results = run_query(&source, request(&["a"]), Duration::ZERO, |_| polls += 1)
+            .await
+            .unwrap();
+
+        assert_eq!(polls, 2);
+        assert_eq!(results.rows, vec![row(&[("@message", "one")])]);
+        assert_eq!(source.queries(), vec![request(&["a"])]);
+    }
+
+    #[tokio::test]
+    async fn reports_missing_groups() {
+        let source = MemoryLogSource::new();
+        let err = run_query(&source, request(&["missing"]), Duration::ZERO, |_| {})
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind, SourceErrorKind::NotFound);
+    }
+
+    fn group(region: &str, name: &str) -> WatchGroup {
+        let mut group = test_support::group(name);
+        group.context.region = region.to_string();
+        group
+    }
+
+    #[tokio::test]
+    async fn queries_each_region_once() {
+        let groups = [
+            group("eu-west-1", "a"),
+            group("us-east-1", "b"),
+            group("eu-west-1", "c"),
+        ];
+        let mut sources = HashMap::new();
+        for (group, message) in groups.iter().zip(["one", "two"]) {
+            let source = MemoryLogSource::new();
+            for name in ["a", "b", "c"] {
+                source.add_group(name);
+            }
+            source.set_query_results(vec![row(&[("@message", message)])], 0);
+            sources.insert(group.context.clone(), source);
+        }
+
+        let (table, errors) =
+            query_groups(&sources, &groups, &query(), Duration::ZERO, |_, _| {}).await;
+
+        assert!(errors.is_empty());
+        assert_eq!(table.columns, vec!["@source", "@message"]);
+        assert_eq!(
+            table.rows,
+            vec![vec!["eu-west-1", "one"], vec!["us-east-1", "two"]]
+        );
+        assert_eq!(
+            sources[&groups[0].context].queries(),
+            vec![request(&["a", "c"])]
+        );
+        assert_eq!(sources[&groups[1].context].queries(), vec![request(&["b"])]);
+    }
+
+    #[tokio::test]
+    async fn reports_failed_queries() {
+        let groups = [group("eu-west-1", "a"), group("us-east-1", "missing")];
+        let mut sources = HashMap::new();
+        for group in &groups {
+            let source = MemoryLogSource::new();
+            source.add_group("a");
+            source.set_query_results(vec![row(&[("@message", "one")])], 0);
+            sources.insert(group.context.clone(), source);
+        }
+
+        let (table, errors) =
+            query_groups(&sources, &groups, &query(), Duration::ZERO, |_, _| {}).await;
+
+        assert_eq!(table.rows, vec![vec!["eu-west-1", "one"]]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, "us-east-1");
+        assert_eq!(errors[0].1.kind, SourceErrorKind::NotFound);
+    }
+
+    #[test]
+    fn collects_columns() {
+        let table = table();
+        assert_eq!(table.columns, vec!["@timestamp", "@message", "status"]);
+        assert_eq!(
+            table.rows,
+            vec![
+                vec!["2022-04-15 05:20:00.123", "hello, world", ""],
+                vec!["2022-04-15 05:20:01.000", "", "500"],
+            ]
+        );
+    }
+
+    #[test]
+    fn renders_tables() {
+        assert_eq!(
+            table().render(QueryFormat::Table),
+            [
+                "@timestamp               @message      status",
+                "-----------------------  ------------  ------",
+                "2022-04-15 05:20:00.123  hello, world",
+                "2022-04-15 05:20:01.000                500",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn renders_csv() {
+        assert_eq!(
+            table().render(QueryFormat::Csv),
+            [
+                "@timestamp,@message,status",
+                "2022-04-15 05:20:00.123,\"hello, world\",",
+                "2022-04-15 05:20:01.000,,500",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn renders_json() {
+        assert_eq!(
+            table().render(QueryFormat::Json),
+            [
+                r#"{"@timestamp":"2022-04-15 05:20:00.123","@message":"hello, world"}"#,
+                r#"{"@timestamp":"2022-04-15 05:20:01.000","status":"500"}"#,
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn labels_rows() {
+        let mut table = table();
+        table.label_rows("@source", "eu-west-1", 0..1);
+        table.label_rows("@source", "us-east-1", 1..2);
+        assert_eq!(table.columns[0], "@source");
+        assert_eq!(table.rows[0][0], "eu-west-1");
+        assert_eq!(table.rows[1][0], "us-east-1");
+    }
+}
@@ -0,0 +1,167 @@
+use std::time::Duration;
+
+use humantime::{parse_duration, parse_rfc3339_weak, DurationError};
+
+use crate::event::unix_millis;
+use time::OffsetDateTime;
+
+/// Parse a `--since` duration, which also accepts a bare `0` to mean "from now on".
+pub fn parse_since(since: &str) -> Result<Duration, DurationError> {
+    if since.trim() == "0" {
+        Ok(Duration::ZERO)
+    } else {
+        parse_duration(since)
+    }
+}
+
+/// Parse an RFC 3339 timestamp given with the named option.
+fn parse_timestamp(option: &str, timestamp: &str) -> Result<OffsetDateTime, String> {
+    parse_rfc3339_weak(timestamp)
+        .map(OffsetDateTime::from)
+        .map_err(|err| format!("Invalid {} timestamp: {}", option, err))
+}
+
+/// Parse `--since` or `--from` into the start of a range of time. Without either, it starts
+/// `default_since` before `now`, or is an error if there's no default.
+pub fn parse_start_time(
+    since: Option<&str>,
+    from: Option<&str>,
+    default_since: Option<Duration>,
+    now: OffsetDateTime,
+) -> Result<OffsetDateTime, String> {
+    match (since, from, default_since) {
+        (Some(_), Some(_), _) => Err("Only one of --since and --from can be given".into()),
+        (Some(since), None, _) => parse_since(since)
+            .map(|since| now - since)
+            .map_err(|err| format!("Invalid --since duration: {}", err)),
+        (None, Some(from), _) => parse_timestamp("--from", from),
+        (None, None, Some(default_since)) => Ok(now - default_since),
+        (None, None, None) => Err("One of --since or --from must be given".into()),
+    }
+}
+
+/// Parse `--since` or `--from` into the start of a range of time, as [`parse_start_time`] does,
+/// and `--to` into its end, which defaults to `now`.
+pub fn parse_time_range(
+    since: Option<&str>,
+    from: Option<&str>,
+    to: Option<&str>,
+    default_since: Option<Duration>,
+    now: OffsetDateTime,
+) -> Result<(OffsetDateTime, OffsetDateTime), String> {
+    let start_time = parse_start_time(since, from, default_since, now)?;
+    let end_time = match to {
+        Some(to) => parse_timestamp("--to", to)?,
+        None => now,
+    };
+    if end_time < start_time {
+        return Err("The end of the time range is before the start".into());
+    }
+
+    Ok((start_time, end_time))
+}
+
+/// Parse `--until`, which is either a duration or an RFC 3339 timestamp, into how long we have left
+/// from `now`. A time that has already passed leaves no time at all.
+pub fn parse_until(until: &str, now: OffsetDateTime) -> Result<Duration, String> {
+    match parse_duration(until) {
+        Ok(duration) => Ok(duration),
+        Err(_) => {
+            let until = parse_timestamp("--until", until)
+                .map_err(|_| format!("Invalid --until duration or timestamp: {}", until))?;
+            Ok(Duration::from_millis(
+                (unix_millis(until) - unix_millis(now)).max(0) as u64,
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::from_unix_millis;
+
+    fn now() -> OffsetDateTime {
+        from_unix_millis(1_650_000_000_000)
+    }
+
+    #[test]
+    fn parses_since_zero() {
+        assert_eq!(parse_since("0"), Ok(Duration::ZERO));
+        assert_eq!(parse_since("5m"), Ok(Duration::from_secs(300)));
+    }
+
+    #[test]
+    fn starts_from_since_or_from() {
+        assert_eq!(
+            parse_time_range(Some("1h"), None, None, None, now()),
+            Ok((now() - Duration::from_secs(3600), now()))
+        );
+        assert_eq!(
+            parse_time_range(
+                None,
+                Some("2022-04-15T05:00:00Z"),
+                Some("2022-04-15T05:10:00Z"),
+                None,
+                now()
+            ),
+            Ok((
+                from_unix_millis(1_649_998_800_000),
+                from_unix_millis(1_649_999_400_000)
+            ))
+        );
+    }
+
+    #[test]
+    fn starts_from_the_default() {
+        assert_eq!(
+            parse_time_range(None, None, None, Some(Duration::from_secs(600)), now()),
+            Ok((now() - Duration::from_secs(600), now()))
+        );
+        assert_eq!(
+            parse_time_range(None, None, None, None, now()),
+            Err("One of --since or --from must be given".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        assert_eq!(
+            parse_time_range(Some("1h"), Some("2022-04-15T05:00:00Z"), None, None, now()),
+            Err("Only one of --since and --from can be given".to_string())
+        );
+        assert_eq!(
+            parse_time_range(
+                None,
+                Some("2022-04-15T05:00:00Z"),
+                Some("2022-04-15T04:00:00Z"),
+                None,
+                now()
+            ),
+            Err("The end of the time range is before the start".to_string())
+        );
+        assert!(parse_time_range(Some("soon"), None, None, None, now())
+            .unwrap_err()
+            .starts_with("Invalid --since duration: "));
+        assert!(parse_time_range(None, Some("yesterday"), None, None, now())
+            .unwrap_err()
+            .starts_with("Invalid --from timestamp: "));
+    }
+
+    #[test]
+    fn parses_until_durations_and_timestamps() {
+        assert_eq!(parse_until("10m", now()), Ok(Duration::from_secs(600)));
+        assert_eq!(
+            parse_until("2022-04-15T05:30:00Z", now()),
+            Ok(Duration::from_secs(600))
+        );
+        assert_eq!(
+            parse_until("2022-04-15T05:00:00Z", now()),
+            Ok(Duration::ZERO)
+        );
+        assert_eq!(
+            parse_until("later", now()),
+            Err("Invalid --until duration or timestamp: later".to_string())
+        );
+    }
+}
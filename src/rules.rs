@@ -0,0 +1,457 @@
+use std::{fmt::Display, ops::Range, str::FromStr};
+
+use console::Style;
+use regex::Regex;
+
+use crate::{
+    config::{ColorConfig, RuleConfig},
+    message::find_json,
+};
+
+/// The severity of a log message, from least to most severe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+    Fatal,
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "trace" => Ok(Level::Trace),
+            "debug" => Ok(Level::Debug),
+            "info" => Ok(Level::Info),
+            "warn" | "warning" => Ok(Level::Warn),
+            "error" => Ok(Level::Error),
+            "fatal" | "critical" => Ok(Level::Fatal),
+            _ => Err(format!(
+                "unknown level '{}' (expected trace, debug, info, warn, error or fatal)",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Level::Trace => "trace",
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+            Level::Fatal => "fatal",
+        })
+    }
+}
+
+/// The priority given to rules from the configuration file or command-line that don't specify
+/// one, which puts them ahead of the default level rules.
+pub const DEFAULT_RULE_PRIORITY: i32 = 100;
+
+/// Pairs a pattern with the style of the messages (or parts of messages) that match it.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub pattern: Regex,
+    pub style: Style,
+    /// The level of the messages that match this rule, if it detects one.
+    pub level: Option<Level>,
+    /// Rules with a higher priority are tried first.
+    pub priority: i32,
+    /// Style only the matched text, rather than the whole message.
+    pub span: bool,
+}
+
+/// An error in a rule from the configuration file or command-line.
+#[derive(Debug)]
+pub enum RuleError {
+    Pattern(String, regex::Error),
+    Level(String),
+}
+
+impl Display for RuleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuleError::Pattern(pattern, err) => {
+                write!(f, "invalid pattern '{}': {}", pattern, err)
+            }
+            RuleError::Level(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for RuleError {}
+
+/// The rules used to detect the level of messages and style them in text output.
+#[derive(Debug, Clone)]
+pub struct Rules {
+    /// Sorted by descending priority, keeping the order in which rules were given for ties.
+    rules: Vec<Rule>,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Self::new(default_rules(&ColorConfig::default()))
+    }
+}
+
+/// The style used for each level when none is configured.
+fn default_level_style(level: Level) -> Style {
+    match level {
+        Level::Trace => Style::new().dim(),
+        Level::Debug => Style::new().dim(),
+        Level::Info => Style::new().blue(),
+        Level::Warn => Style::new().yellow(),
+        Level::Error => Style::new().red(),
+        Level::Fatal => Style::new().red().bold(),
+    }
+}
+
+/// Get the style for a level, preferring any given in the configuration.
+fn level_style(colors: &ColorConfig, level: Level) -> Style {
+    let configured = match level {
+        Level::Trace => &colors.trace,
+        Level::Debug => &colors.debug,
+        Level::Info => &colors.info,
+        Level::Warn => &colors.warn,
+        Level::Error => &colors.error,
+        Level::Fatal => &colors.fatal,
+    };
+
+    configured
+        .as_deref()
+        .map(Style::from_dotted_str)
+        .unwrap_or_else(|| default_level_style(level))
+}
+
+/// The rules that recognize the usual level keywords. More severe levels take priority, so that
+/// a line containing both `INFO` and `ERROR` is treated as an error.
+fn default_rules(colors: &ColorConfig) -> Vec<Rule> {
+    [
+        (Level::Fatal, r"\b(?:FATAL|CRITICAL)\b"),
+        (Level::Error, r"\bERROR\b"),
+        (Level::Warn, r"\bWARN(?:ING)?\b"),
+        (Level::Info, r"\bINFO\b"),
+        (Level::Debug, r"\bDEBUG\b"),
+        (Level::Trace, r"\bTRACE\b"),
+    ]
+    .into_iter()
+    .map(|(level, pattern)| Rule {
+        pattern: Regex::new(pattern).unwrap(),
+        style: level_style(colors, level),
+        level: Some(level),
+        priority: (level as i32 + 1) * 10,
+        span: false,
+    })
+    .collect()
+}
+
+impl Rules {
+    pub fn new(mut rules: Vec<Rule>) -> Self {
+        rules.sort_by_key(|rule| -rule.priority);
+        Self { rules }
+    }
+
+    /// Build the rules from the configuration, followed by the default level rules.
+    pub fn from_config(configs: &[RuleConfig], colors: &ColorConfig) -> Result<Self, RuleError> {
+        let mut rules = Vec::new();
+        for config in configs {
+            let pattern = Regex::new(&config.pattern)
+                .map_err(|err| RuleError::Pattern(config.pattern.clone(), err))?;
+            let level = config
+                .level
+                .as_deref()
+                .map(Level::from_str)
+                .transpose()
+                .map_err(RuleError::Level)?;
+
+            // A rule that sets a level but no style looks like any other message of that level
+            let style = match (&config.style, level) {
+                (Some(style), _) => Style::from_dotted_str(style),
+                (None, Some(level)) => level_style(colors, level),
+                (None, None) => Style::new(),
+            };
+
+            rules.push(Rule {
+                pattern,
+                style,
+                level,
+                priority: config.priority.unwrap_or(DEFAULT_RULE_PRIORITY),
+                span: config.span,
+            });
+        }
+
+        rules.extend(default_rules(colors));
+        Ok(Self::new(rules))
+    }
+
+    /// Detect the level of the message, preferring a `level` or `severity` field if the message
+    /// is a JSON object, and otherwise using the rules.
+    pub fn detect_level(&self, message: &str) -> Option<Level> {
+        structured_level(message).or_else(|| self.level(message))
+    }
+
+    /// Detect the level of the message from the highest priority rule that matches it.
+    pub fn level(&self, message: &str) -> Option<Level> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.level.is_some())
+            .find(|rule| rule.pattern.is_match(message))
+            .and_then(|rule| rule.level)
+    }
+
+    /// Style the message. The highest priority whole-message rule that matches sets the style of
+    /// the message, and each span rule styles the text it matches on top of that.
+    pub fn render(&self, message: &str) -> String {
+        let line_style = self
+            .rules
+            .iter()
+            .filter(|rule| !rule.span)
+            .find(|rule| rule.pattern.is_match(message))
+            .map(|rule| &rule.style);
+
+        // Higher priority spans claim their text first
+        let mut spans: Vec<(Range<usize>, &Style)> = Vec::new();
+        for rule in self.rules.iter().filter(|rule| rule.span) {
+            for found in rule.pattern.find_iter(message) {
+                let range = found.range();
+                if !range.is_empty()
+                    && !spans
+                        .iter()
+                        .any(|(span, _)| span.start < range.end && range.start < span.end)
+                {
+                    spans.push((range, &rule.style));
+                }
+            }
+        }
+        spans.sort_by_key(|(span, _)| span.start);
+
+        let plain = Style::new();
+        let line_style = line_style.unwrap_or(&plain);
+        let mut rendered = String::new();
+        let mut offset = 0;
+        for (span, style) in spans {
+            if offset < span.start {
+                rendered.push_str(
+                    &line_style
+                        .apply_to(&message[offset..span.start])
+                        .to_string(),
+                );
+            }
+            rendered.push_str(&style.apply_to(&message[span.clone()]).to_string());
+            offset = span.end;
+        }
+        if offset < message.len() {
+            rendered.push_str(&line_style.apply_to(&message[offset..]).to_string());
+        }
+
+        rendered
+    }
+}
+
+/// Hides messages below a minimum level.
+#[derive(Debug, Clone)]
+pub struct LevelFilter {
+    min_level: Level,
+    rules: Rules,
+}
+
+impl LevelFilter {
+    pub fn new(min_level: Level, rules: Rules) -> Self {
+        Self { min_level, rules }
+    }
+
+    /// Whether the message is at or above the minimum level. Messages whose level we can't detect
+    /// are hidden, as they are usually noise such as Lambda's `START` and `REPORT` lines.
+    pub fn matches(&self, message: &str) -> bool {
+        self.rules
+            .detect_level(message)
+            .is_some_and(|level| level >= self.min_level)
+    }
+}
+
+/// Get the level from the `level` or `severity` field of a JSON message, which may follow a text
+/// prefix. Numeric levels are read as used by pino and bunyan, where 30 is info and 50 is error.
+fn structured_level(message: &str) -> Option<Level> {
+    let (_, value) = find_json(message)?;
+    let field = value.get("level").or_else(|| value.get("severity"))?;
+    match field {
+        serde_json::Value::String(level) => level.parse().ok(),
+        serde_json::Value::Number(level) => match level.as_u64()? {
+            0..=10 => Some(Level::Trace),
+            11..=20 => Some(Level::Debug),
+            21..=30 => Some(Level::Info),
+            31..=40 => Some(Level::Warn),
+            41..=50 => Some(Level::Error),
+            _ => Some(Level::Fatal),
+        },
+        _ => None,
+    }
+}
+
+/// Parse a rule given on the command-line as `PATTERN=STYLE`. The style is taken from after the
+/// last `=`, as patterns are more likely to contain one.
+pub fn parse_rule_arg(arg: &str, span: bool) -> Option<RuleConfig> {
+    let (pattern, style) = arg.rsplit_once('=')?;
+    Some(RuleConfig {
+        pattern: pattern.to_string(),
+        style: Some(style.to_string()),
+        level: None,
+        priority: None,
+        span,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(pattern: &str, style: &str, span: bool) -> RuleConfig {
+        RuleConfig {
+            pattern: pattern.to_string(),
+            style: Some(style.to_string()),
+            level: None,
+            priority: None,
+            span,
+        }
+    }
+
+    #[test]
+    fn detects_default_levels() {
+        let rules = Rules::default();
+        assert_eq!(rules.level("TRACE entering"), Some(Level::Trace));
+        assert_eq!(rules.level("DEBUG cache miss"), Some(Level::Debug));
+        assert_eq!(rules.level("INFO started"), Some(Level::Info));
+        assert_eq!(rules.level("WARNING disk low"), Some(Level::Warn));
+        assert_eq!(rules.level("CRITICAL out of memory"), Some(Level::Fatal));
+        assert_eq!(rules.level("nothing to see"), None);
+        assert_eq!(rules.level("INFORMATION"), None);
+    }
+
+    #[test]
+    fn more_severe_levels_win() {
+        let rules = Rules::default();
+        assert_eq!(
+            rules.level("INFO request failed with ERROR"),
+            Some(Level::Error)
+        );
+    }
+
+    #[test]
+    fn prefers_structured_levels() {
+        let rules = Rules::default();
+        assert_eq!(
+            rules.detect_level(r#"{"level":"warn","msg":"ERROR in upstream"}"#),
+            Some(Level::Warn)
+        );
+        assert_eq!(
+            rules.detect_level(r#"{"severity":"CRITICAL","msg":"down"}"#),
+            Some(Level::Fatal)
+        );
+        assert_eq!(
+            rules.detect_level(r#"{"level":50,"msg":"failed"}"#),
+            Some(Level::Error)
+        );
+        assert_eq!(
+            rules.detect_level(r#"{"msg":"ERROR no level field"}"#),
+            Some(Level::Error)
+        );
+        assert_eq!(
+            rules.detect_level("2022-04-15T05:20:00Z\tabc\tINFO\t{\"level\":\"error\"}"),
+            Some(Level::Error)
+        );
+        assert_eq!(rules.detect_level("{not json} INFO"), Some(Level::Info));
+    }
+
+    #[test]
+    fn filters_by_level() {
+        let filter = LevelFilter::new(Level::Warn, Rules::default());
+        assert!(filter.matches("WARN slow request"));
+        assert!(filter.matches("ERROR failed"));
+        assert!(filter.matches(r#"{"level":"fatal"}"#));
+        assert!(!filter.matches("INFO started"));
+        assert!(!filter.matches(r#"{"level":"debug","msg":"ERROR"}"#));
+        assert!(!filter.matches("REPORT RequestId: 1234"));
+    }
+
+    #[test]
+    fn configured_rules_take_priority() {
+        let mut panic = rule("panicked at", "magenta", false);
+        panic.level = Some("fatal".to_string());
+        let mut noisy = rule("healthcheck", "dim", false);
+        noisy.level = Some("debug".to_string());
+        noisy.priority = Some(5);
+
+        let rules = Rules::from_config(&[panic, noisy], &ColorConfig::default()).unwrap();
+        assert_eq!(
+            rules.level("thread panicked at src/main.rs"),
+            Some(Level::Fatal)
+        );
+        assert_eq!(rules.level("INFO healthcheck ok"), Some(Level::Info));
+        assert_eq!(rules.level("healthcheck ok"), Some(Level::Debug));
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        let err = Rules::from_config(&[rule("(", "red", false)], &ColorConfig::default());
+        assert!(matches!(err, Err(RuleError::Pattern(..))));
+
+        let mut bad_level = rule("x", "red", false);
+        bad_level.level = Some("loud".to_string());
+        let err = Rules::from_config(&[bad_level], &ColorConfig::default());
+        assert!(matches!(err, Err(RuleError::Level(_))));
+    }
+
+    #[test]
+    fn renders_whole_lines_and_spans() {
+        // Force styling, as other tests turn colors off
+        let red = Style::new().red().force_styling(true);
+        let cyan = Style::new().cyan().force_styling(true);
+        let rules = Rules::new(vec![
+            Rule {
+                pattern: Regex::new(r"\bERROR\b").unwrap(),
+                style: red.clone(),
+                level: Some(Level::Error),
+                priority: 50,
+                span: false,
+            },
+            Rule {
+                pattern: Regex::new(r"req-\d+").unwrap(),
+                style: cyan.clone(),
+                level: None,
+                priority: DEFAULT_RULE_PRIORITY,
+                span: true,
+            },
+        ]);
+
+        assert_eq!(
+            rules.render("ERROR req-42 failed"),
+            format!(
+                "{}{}{}",
+                red.apply_to("ERROR "),
+                cyan.apply_to("req-42"),
+                red.apply_to(" failed")
+            )
+        );
+        assert_eq!(
+            rules.render("req-1 ok"),
+            format!("{} ok", cyan.apply_to("req-1"))
+        );
+        assert_eq!(rules.render("plain"), "plain");
+    }
+
+    #[test]
+    fn parses_rule_args() {
+        let rule = parse_rule_arg("a=b=yellow.bold", true).unwrap();
+        assert_eq!(rule.pattern, "a=b");
+        assert_eq!(rule.style.as_deref(), Some("yellow.bold"));
+        assert!(rule.span);
+        assert!(parse_rule_arg("no style", false).is_none());
+    }
+}
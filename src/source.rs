@@ -0,0 +1,524 @@
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use async_trait::async_trait;
+use aws_sdk_cloudwatchlogs::{
+    error::{DescribeLogGroupsError, FilterLogEventsError, GetQueryResultsError, StartQueryError},
+    model,
+    types::SdkError,
+    Client, Error,
+};
+
+/// A page of log group names returned by [`LogSource::describe_log_groups`].
+#[derive(Debug, Clone, Default)]
+pub struct LogGroupsPage {
+    pub names: Vec<String>,
+    pub next_token: Option<String>,
+}
+
+/// The parameters for [`LogSource::filter_log_events`].
+#[derive(Debug, Clone, Default)]
+pub struct FilterRequest {
+    pub group: String,
+    pub filter_pattern: Option<String>,
+    pub stream_prefix: Option<String>,
+    pub stream_names: Vec<String>,
+    /// The earliest event timestamp (in milliseconds) to return.
+    pub start_time: i64,
+    /// The latest event timestamp (in milliseconds) to return, if any.
+    pub end_time: Option<i64>,
+    /// The most events to return in this page.
+    pub limit: usize,
+    pub next_token: Option<String>,
+}
+
+/// An event returned by [`LogSource::filter_log_events`].
+#[derive(Debug, Clone, Default)]
+pub struct SourceEvent {
+    pub event_id: String,
+    pub stream: String,
+    /// The event timestamp, in milliseconds since the Unix epoch.
+    pub timestamp: i64,
+    /// The time the event was ingested, in milliseconds since the Unix epoch.
+    pub ingestion_time: i64,
+    pub message: String,
+}
+
+/// A page of events returned by [`LogSource::filter_log_events`].
+#[derive(Debug, Clone, Default)]
+pub struct EventsPage {
+    pub events: Vec<SourceEvent>,
+    pub next_token: Option<String>,
+}
+
+/// The parameters for [`LogSource::start_query`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueryRequest {
+    pub groups: Vec<String>,
+    /// The Logs Insights query.
+    pub query: String,
+    /// The start of the time range (in milliseconds) to query.
+    pub start_time: i64,
+    /// The end of the time range (in milliseconds) to query.
+    pub end_time: i64,
+    /// The most results to return.
+    pub limit: Option<usize>,
+}
+
+/// The state of a Logs Insights query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryStatus {
+    Scheduled,
+    Running,
+    Complete,
+    Failed,
+    Cancelled,
+    Timeout,
+    Unknown,
+}
+
+impl QueryStatus {
+    /// Whether the query has stopped, successfully or not.
+    pub fn is_finished(self) -> bool {
+        !matches!(self, QueryStatus::Scheduled | QueryStatus::Running)
+    }
+}
+
+/// The results of a Logs Insights query so far, returned by [`LogSource::get_query_results`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryResults {
+    pub status: QueryStatus,
+    /// Each row is a list of field names and values.
+    pub rows: Vec<Vec<(String, String)>>,
+    pub records_matched: f64,
+    pub records_scanned: f64,
+}
+
+/// The kinds of error that we handle differently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceErrorKind {
+    NotFound,
+    Throttled,
+    Other,
+}
+
+#[derive(Debug, Clone)]
+pub struct SourceError {
+    pub kind: SourceErrorKind,
+    pub message: String,
+}
+
+impl SourceError {
+    pub fn new(kind: SourceErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for SourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.message.fmt(f)
+    }
+}
+
+impl std::error::Error for SourceError {}
+
+/// The calls we make to CloudWatch Logs, so that they can be faked out in tests.
+#[async_trait]
+pub trait LogSource: Send + Sync {
+    /// Get a page of log group names with the given prefix.
+    async fn describe_log_groups(
+        &self,
+        prefix: Option<String>,
+        limit: usize,
+        next_token: Option<String>,
+    ) -> Result<LogGroupsPage, SourceError>;
+
+    /// Get a page of events from a log group.
+    async fn filter_log_events(&self, request: FilterRequest) -> Result<EventsPage, SourceError>;
+
+    /// Start a Logs Insights query, returning its ID.
+    async fn start_query(&self, request: QueryRequest) -> Result<String, SourceError>;
+
+    /// Get the status and results of a Logs Insights query.
+    async fn get_query_results(&self, query_id: &str) -> Result<QueryResults, SourceError>;
+}
+
+/// A [`LogSource`] that talks to CloudWatch Logs through the AWS SDK.
+#[derive(Debug, Clone)]
+pub struct SdkLogSource {
+    client: Client,
+}
+
+impl SdkLogSource {
+    pub fn new(client: Client) -> Self {
+        Self { client }
+    }
+}
+
+/// A [`LogSource`] that counts the requests made through it.
+#[derive(Debug, Default)]
+pub struct CountingLogSource<S> {
+    source: S,
+    requests: AtomicUsize,
+}
+
+impl<S> CountingLogSource<S> {
+    pub fn new(source: S) -> Self {
+        Self {
+            source,
+            requests: AtomicUsize::new(0),
+        }
+    }
+
+    /// The source that requests are passed on to.
+    pub fn inner(&self) -> &S {
+        &self.source
+    }
+
+    /// How many requests have been made so far.
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    fn count(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[async_trait]
+impl<S: LogSource> LogSource for CountingLogSource<S> {
+    async fn describe_log_groups(
+        &self,
+        prefix: Option<String>,
+        limit: usize,
+        next_token: Option<String>,
+    ) -> Result<LogGroupsPage, SourceError> {
+        self.count();
+        self.source
+            .describe_log_groups(prefix, limit, next_token)
+            .await
+    }
+
+    async fn filter_log_events(&self, request: FilterRequest) -> Result<EventsPage, SourceError> {
+        self.count();
+        self.source.filter_log_events(request).await
+    }
+
+    async fn start_query(&self, request: QueryRequest) -> Result<String, SourceError> {
+        self.count();
+        self.source.start_query(request).await
+    }
+
+    async fn get_query_results(&self, query_id: &str) -> Result<QueryResults, SourceError> {
+        self.count();
+        self.source.get_query_results(query_id).await
+    }
+}
+
+/// The error codes that AWS uses to tell us that we are being throttled.
+const THROTTLING_ERROR_CODES: &[&str] = &[
+    "Throttling",
+    "ThrottlingException",
+    "ThrottledException",
+    "RequestThrottledException",
+    "TooManyRequestsException",
+    "RequestLimitExceeded",
+];
+
+fn is_throttling_code(code: Option<&str>) -> bool {
+    code.is_some_and(|code| THROTTLING_ERROR_CODES.contains(&code))
+}
+
+impl From<SdkError<DescribeLogGroupsError>> for SourceError {
+    fn from(err: SdkError<DescribeLogGroupsError>) -> Self {
+        let kind = match &err {
+            SdkError::ServiceError { err, .. } if is_throttling_code(err.code()) => {
+                SourceErrorKind::Throttled
+            }
+            _ => SourceErrorKind::Other,
+        };
+
+        SourceError::new(kind, Error::from(err).to_string())
+    }
+}
+
+impl From<SdkError<FilterLogEventsError>> for SourceError {
+    fn from(err: SdkError<FilterLogEventsError>) -> Self {
+        let kind = match &err {
+            SdkError::ServiceError { err, .. } if err.is_resource_not_found_exception() => {
+                SourceErrorKind::NotFound
+            }
+            SdkError::ServiceError { err, .. } if is_throttling_code(err.code()) => {
+                SourceErrorKind::Throttled
+            }
+            _ => SourceErrorKind::Other,
+        };
+
+        SourceError::new(kind, Error::from(err).to_string())
+    }
+}
+
+impl From<SdkError<StartQueryError>> for SourceError {
+    fn from(err: SdkError<StartQueryError>) -> Self {
+        let kind = match &err {
+            SdkError::ServiceError { err, .. } if err.is_resource_not_found_exception() => {
+                SourceErrorKind::NotFound
+            }
+            SdkError::ServiceError { err, .. } if is_throttling_code(err.code()) => {
+                SourceErrorKind::Throttled
+            }
+            _ => SourceErrorKind::Other,
+        };
+
+        SourceError::new(kind, Error::from(err).to_string())
+    }
+}
+
+impl From<SdkError<GetQueryResultsError>> for SourceError {
+    fn from(err: SdkError<GetQueryResultsError>) -> Self {
+        let kind = match &err {
+            SdkError::ServiceError { err, .. } if is_throttling_code(err.code()) => {
+                SourceErrorKind::Throttled
+            }
+            _ => SourceErrorKind::Other,
+        };
+
+        SourceError::new(kind, Error::from(err).to_string())
+    }
+}
+
+impl From<&model::QueryStatus> for QueryStatus {
+    fn from(status: &model::QueryStatus) -> Self {
+        match status {
+            model::QueryStatus::Scheduled => QueryStatus::Scheduled,
+            model::QueryStatus::Running => QueryStatus::Running,
+            model::QueryStatus::Complete => QueryStatus::Complete,
+            model::QueryStatus::Failed => QueryStatus::Failed,
+            model::QueryStatus::Cancelled => QueryStatus::Cancelled,
+            model::QueryStatus::Timeout => QueryStatus::Timeout,
+            _ => QueryStatus::Unknown,
+        }
+    }
+}
+
+#[async_trait]
+impl LogSource for SdkLogSource {
+    async fn describe_log_groups(
+        &self,
+        prefix: Option<String>,
+        limit: usize,
+        next_token: Option<String>,
+    ) -> Result<LogGroupsPage, SourceError> {
+        let res = self
+            .client
+            .describe_log_groups()
+            .set_log_group_name_prefix(prefix)
+            .set_next_token(next_token)
+            .limit(limit as i32)
+            .send()
+            .await?;
+
+        Ok(LogGroupsPage {
+            names: res
+                .log_groups
+                .unwrap_or_default()
+                .into_iter()
+                .map(|group| group.log_group_name.unwrap_or_default())
+                .collect(),
+            next_token: res.next_token,
+        })
+    }
+
+    async fn filter_log_events(&self, request: FilterRequest) -> Result<EventsPage, SourceError> {
+        let FilterRequest {
+            group,
+            filter_pattern,
+            stream_prefix,
+            stream_names,
+            start_time,
+            end_time,
+            limit,
+            next_token,
+        } = request;
+
+        let res = self
+            .client
+            .filter_log_events()
+            .log_group_name(group)
+            .set_filter_pattern(filter_pattern)
+            .set_log_stream_name_prefix(stream_prefix)
+            .set_log_stream_names(if stream_names.is_empty() {
+                None
+            } else {
+                Some(stream_names)
+            })
+            .limit(limit as i32)
+            .start_time(start_time)
+            .set_end_time(end_time)
+            .set_next_token(next_token)
+            .send()
+            .await?;
+
+        Ok(EventsPage {
+            events: res
+                .events
+                .unwrap_or_default()
+                .into_iter()
+                .map(|event| SourceEvent {
+                    event_id: event.event_id.unwrap_or_default(),
+                    stream: event.log_stream_name.unwrap_or_default(),
+                    timestamp: event.timestamp.unwrap_or_default(),
+                    ingestion_time: event.ingestion_time.unwrap_or_default(),
+                    message: event
+                        .message
+                        .map(|msg| msg.trim().to_string())
+                        .unwrap_or_default(),
+                })
+                .collect(),
+            next_token: res.next_token,
+        })
+    }
+
+    async fn start_query(&self, request: QueryRequest) -> Result<String, SourceError> {
+        let QueryRequest {
+            groups,
+            query,
+            start_time,
+            end_time,
+            limit,
+        } = request;
+
+        // Logs Insights works in seconds rather than milliseconds
+        let res = self
+            .client
+            .start_query()
+            .set_log_group_names(Some(groups))
+            .query_string(query)
+            .start_time(start_time / 1000)
+            .end_time(end_time / 1000)
+            .set_limit(limit.map(|limit| limit as i32))
+            .send()
+            .await?;
+
+        Ok(res.query_id.unwrap_or_default())
+    }
+
+    async fn get_query_results(&self, query_id: &str) -> Result<QueryResults, SourceError> {
+        let res = self
+            .client
+            .get_query_results()
+            .query_id(query_id)
+            .send()
+            .await?;
+
+        let statistics = res.statistics();
+        Ok(QueryResults {
+            status: res
+                .status()
+                .map(QueryStatus::from)
+                .unwrap_or(QueryStatus::Unknown),
+            records_matched: statistics.map_or(0.0, |statistics| statistics.records_matched()),
+            records_scanned: statistics.map_or(0.0, |statistics| statistics.records_scanned()),
+            rows: res
+                .results()
+                .unwrap_or_default()
+                .iter()
+                .map(|row| {
+                    row.iter()
+                        .map(|field| {
+                            (
+                                field.field().unwrap_or_default().to_string(),
+                                field.value().unwrap_or_default().to_string(),
+                            )
+                        })
+                        .collect()
+                })
+                .collect(),
+        })
+    }
+}
+
+/// The maximum number of log groups that `DescribeLogGroups` will return in a single page.
+const DESCRIBE_LOG_GROUPS_PAGE_SIZE: usize = 50;
+
+/// Get the names of all the log groups with the given prefix, up to an optional limit.
+pub async fn list_log_groups<S: LogSource + ?Sized>(
+    source: &S,
+    prefix: Option<String>,
+    limit: Option<usize>,
+) -> Result<Vec<String>, SourceError> {
+    let mut names = Vec::new();
+    let mut next_token = None;
+
+    loop {
+        let page_size = limit
+            .map(|limit| (limit - names.len()).min(DESCRIBE_LOG_GROUPS_PAGE_SIZE))
+            .unwrap_or(DESCRIBE_LOG_GROUPS_PAGE_SIZE);
+        if page_size == 0 {
+            break;
+        }
+
+        let page = source
+            .describe_log_groups(prefix.clone(), page_size, next_token)
+            .await?;
+        names.extend(page.names);
+
+        // Keep going until there are no more pages
+        next_token = page.next_token;
+        if next_token.is_none() {
+            break;
+        }
+    }
+
+    // Don't trust the service to have respected our page size
+    if let Some(limit) = limit {
+        names.truncate(limit);
+    }
+
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryLogSource;
+
+    fn source_with_groups(count: usize) -> MemoryLogSource {
+        let source = MemoryLogSource::new();
+        for index in 0..count {
+            source.add_group(&format!("/aws/lambda/function-{:03}", index));
+        }
+        source.add_group("/ecs/service");
+        source
+    }
+
+    #[tokio::test]
+    async fn list_walks_every_page() {
+        let source = source_with_groups(120);
+        let names = list_log_groups(&source, None, None).await.unwrap();
+        assert_eq!(names.len(), 121);
+        assert_eq!(source.calls(), 3);
+    }
+
+    #[tokio::test]
+    async fn list_filters_by_prefix() {
+        let source = source_with_groups(10);
+        let names = list_log_groups(&source, Some("/ecs/".to_string()), None)
+            .await
+            .unwrap();
+        assert_eq!(names, vec!["/ecs/service".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn list_stops_at_the_limit() {
+        let source = source_with_groups(120);
+        let names = list_log_groups(&source, None, Some(60)).await.unwrap();
+        assert_eq!(names.len(), 60);
+        assert_eq!(source.calls(), 2);
+    }
+}
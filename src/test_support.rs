@@ -0,0 +1,50 @@
+//! Fixtures shared by the tests of each module.
+
+use std::collections::HashMap;
+
+use time::OffsetDateTime;
+
+use crate::{
+    context::ClientContext,
+    event::{unix_millis, LogEvent},
+    memory::MemoryLogSource,
+    watch::WatchGroup,
+};
+
+/// The context of the groups in tests, unless they say otherwise.
+pub fn context() -> ClientContext {
+    ClientContext {
+        profile: None,
+        role_arn: None,
+        region: "eu-west-1".to_string(),
+        endpoint_url: None,
+    }
+}
+
+/// A group with the test context and no filters.
+pub fn group(name: &str) -> WatchGroup {
+    WatchGroup {
+        context: context(),
+        account: None,
+        name: name.to_string(),
+        filter_pattern: None,
+        stream_prefix: None,
+        stream_names: Vec::new(),
+    }
+}
+
+/// The source for the test context.
+pub fn sources(source: MemoryLogSource) -> HashMap<ClientContext, MemoryLogSource> {
+    let mut sources = HashMap::new();
+    sources.insert(context(), source);
+    sources
+}
+
+/// A timestamp (in milliseconds) the given number of seconds ago.
+pub fn seconds_ago(seconds: i64) -> i64 {
+    unix_millis(OffsetDateTime::now_utc()) - seconds * 1000
+}
+
+pub fn messages(events: &[LogEvent]) -> Vec<&str> {
+    events.iter().map(|event| event.message.as_str()).collect()
+}
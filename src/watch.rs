@@ -0,0 +1,1189 @@
+use std::{
+    borrow::BorrowMut,
+    collections::HashMap,
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use futures::{
+    stream::{self, FuturesUnordered},
+    Stream, StreamExt,
+};
+use time::OffsetDateTime;
+
+use crate::{
+    context::ClientContext,
+    event::{from_unix_millis, group_label, unix_millis, LogEvent},
+    fetch::recent_events,
+    source::{CountingLogSource, FilterRequest, LogSource, SourceError, SourceErrorKind},
+};
+
+/// The most events that `FilterLogEvents` will return in a single page.
+const FILTER_LOG_EVENTS_PAGE_SIZE: usize = 10_000;
+
+/// Limits how much a single group can fetch in one poll, so that a noisy group cannot starve the
+/// others. Anything left over is picked up on the next poll.
+#[derive(Debug, Clone, Copy)]
+pub struct PollBudget {
+    pub max_pages: usize,
+    pub max_events: usize,
+}
+
+impl Default for PollBudget {
+    fn default() -> Self {
+        Self {
+            max_pages: 10,
+            max_events: 1000,
+        }
+    }
+}
+
+/// Where to resume a group's query when it ran out of budget before reaching the last page.
+struct Continuation {
+    start_time: i64,
+    next_token: String,
+}
+
+pub(crate) struct GroupEvents {
+    pub(crate) events: Vec<LogEvent>,
+    /// How many pages were requested.
+    pub(crate) pages: usize,
+    continuation: Option<Continuation>,
+}
+
+impl GroupEvents {
+    /// Whether every event in the window was fetched, rather than running out of budget.
+    pub(crate) fn is_drained(&self) -> bool {
+        self.continuation.is_none()
+    }
+
+    /// The token for the next page, if the budget ran out before the last one.
+    pub(crate) fn next_token(&self) -> Option<&str> {
+        self.continuation
+            .as_ref()
+            .map(|continuation| continuation.next_token.as_str())
+    }
+}
+
+/// A log group as given on the command-line or in a watch set, in the form
+/// `[PROFILE@][REGION:]GROUP`. Instead of a profile, the qualifier can be the ARN of a role to
+/// assume.
+pub struct GroupSpec<'a> {
+    pub qualifier: Option<&'a str>,
+    pub region: Option<&'a str>,
+    pub name: &'a str,
+}
+
+impl<'a> GroupSpec<'a> {
+    /// Parse a group spec. Log group names cannot contain `@` or `:`, so the qualifier is anything
+    /// before the last `@` (role ARNs contain colons), and the region anything before the first
+    /// colon after that.
+    pub fn parse(spec: &'a str) -> Self {
+        let (qualifier, rest) = match spec.rsplit_once('@') {
+            Some((qualifier, rest)) => (Some(qualifier), rest),
+            None => (None, spec),
+        };
+
+        let (region, name) = match rest.split_once(':') {
+            Some((region, name)) => (Some(region), name),
+            None => (None, rest),
+        };
+
+        GroupSpec {
+            qualifier,
+            region,
+            name,
+        }
+    }
+}
+
+/// A log group to watch, along with any options specific to that group.
+#[derive(Debug, Clone)]
+pub struct WatchGroup {
+    /// The credentials and region with which we access the group.
+    pub context: ClientContext,
+    /// The label of the account the group lives in, when watching more than one.
+    pub account: Option<String>,
+    pub name: String,
+    pub filter_pattern: Option<String>,
+    pub stream_prefix: Option<String>,
+    pub stream_names: Vec<String>,
+}
+
+impl WatchGroup {
+    /// A label for the group, which only includes the region if `show_region` is set (see
+    /// [`show_region`]). Events are labelled the same way.
+    pub fn label(&self, show_region: bool) -> String {
+        group_label(
+            self.account.as_deref(),
+            &self.context.region,
+            &self.name,
+            show_region,
+        )
+    }
+
+    /// Whether this is the same log group as the other, accessed in the same way.
+    fn is_same_group(&self, other: &WatchGroup) -> bool {
+        self.name == other.name && self.context == other.context
+    }
+}
+
+impl Display for WatchGroup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.label(true))
+    }
+}
+
+/// Drop any groups that were given more than once, keeping the first.
+fn dedup_groups(groups: Vec<WatchGroup>) -> Vec<WatchGroup> {
+    let mut unique: Vec<WatchGroup> = Vec::with_capacity(groups.len());
+    for group in groups {
+        if !unique.iter().any(|other| other.is_same_group(&group)) {
+            unique.push(group);
+        }
+    }
+
+    unique
+}
+
+/// Parse `--group-filter` options, given as `GROUP=PATTERN`, into the pattern for each group.
+pub fn parse_group_filters(group_filters: &[String]) -> Result<HashMap<String, String>, String> {
+    group_filters
+        .iter()
+        .map(|group_filter| match group_filter.split_once('=') {
+            Some((group, pattern)) => Ok((group.to_string(), pattern.to_string())),
+            None => Err(format!(
+                "Expected GROUP=PATTERN for --group-filter: {}",
+                group_filter
+            )),
+        })
+        .collect()
+}
+
+/// The filters that select which events are fetched from each group.
+#[derive(Debug, Clone, Default)]
+pub struct GroupFilters {
+    /// The filter pattern for groups without one of their own.
+    pub filter: Option<String>,
+    /// The filter pattern for each group, by its spec or its name.
+    pub group_filters: HashMap<String, String>,
+    pub stream_prefix: Option<String>,
+    pub stream_names: Vec<String>,
+}
+
+/// Resolve the group specs into the groups to fetch events from, with `default_context` for any
+/// part of the context that a spec doesn't give. Specs that resolve to the same group are only
+/// included once.
+pub fn resolve_groups(
+    specs: &[String],
+    default_context: &ClientContext,
+    filters: &GroupFilters,
+) -> Vec<WatchGroup> {
+    let groups = specs
+        .iter()
+        .map(|spec| {
+            let (context, name) = resolve_group(spec, default_context);
+            WatchGroup {
+                context,
+                account: None,
+                name: name.to_string(),
+                filter_pattern: filters
+                    .group_filters
+                    .get(spec)
+                    .or_else(|| filters.group_filters.get(name))
+                    .or(filters.filter.as_ref())
+                    .cloned(),
+                stream_prefix: filters.stream_prefix.clone(),
+                stream_names: filters.stream_names.clone(),
+            }
+        })
+        .collect();
+
+    dedup_groups(groups)
+}
+
+/// Resolve a group spec into the context with which we access the group, and the group's name.
+fn resolve_group<'a>(spec: &'a str, default_context: &ClientContext) -> (ClientContext, &'a str) {
+    let GroupSpec {
+        qualifier,
+        region,
+        name,
+    } = GroupSpec::parse(spec);
+    let mut context = match qualifier {
+        Some(qualifier) => default_context.qualified(qualifier),
+        None => default_context.clone(),
+    };
+    if let Some(region) = region {
+        context.region = region.to_string();
+    }
+
+    (context, name)
+}
+
+/// Whether to show the region of each event, which is only needed if there's more than one.
+pub fn show_region(groups: &[WatchGroup]) -> bool {
+    groups
+        .iter()
+        .any(|group| group.context.region != groups[0].context.region)
+}
+
+/// Fetch the group's events from `start_time` (and up to `end_time`, if given), stopping early if
+/// the budget runs out.
+pub(crate) async fn get_group_events<S: LogSource + ?Sized>(
+    source: &S,
+    group: &WatchGroup,
+    start_time: i64,
+    end_time: Option<i64>,
+    mut next_token: Option<String>,
+    budget: PollBudget,
+) -> Result<GroupEvents, SourceError> {
+    let mut events = Vec::new();
+    let mut pages = 0;
+
+    loop {
+        let page = source
+            .filter_log_events(FilterRequest {
+                group: group.name.clone(),
+                filter_pattern: group.filter_pattern.clone(),
+                stream_prefix: group.stream_prefix.clone(),
+                stream_names: group.stream_names.clone(),
+                start_time,
+                end_time,
+                limit: (budget.max_events - events.len()).min(FILTER_LOG_EVENTS_PAGE_SIZE),
+                next_token,
+            })
+            .await?;
+
+        events.extend(page.events.into_iter().map(|event| LogEvent {
+            event_id: event.event_id,
+            account: group.account.clone(),
+            region: group.context.region.clone(),
+            group: group.name.clone(),
+            stream: event.stream,
+            timestamp: from_unix_millis(event.timestamp),
+            ingestion_time: from_unix_millis(event.ingestion_time),
+            message: event.message,
+        }));
+
+        pages += 1;
+        next_token = page.next_token;
+
+        // Stop once the window is drained, or leave the rest for the next poll if we've spent
+        // this group's budget.
+        match next_token {
+            None => {
+                return Ok(GroupEvents {
+                    events,
+                    pages,
+                    continuation: None,
+                })
+            }
+            Some(next_token) if pages >= budget.max_pages || events.len() >= budget.max_events => {
+                return Ok(GroupEvents {
+                    events,
+                    pages,
+                    continuation: Some(Continuation {
+                        start_time,
+                        next_token,
+                    }),
+                })
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Remembers the IDs of the events we've already displayed, along with their timestamps.
+///
+/// IDs are forgotten once their event falls outside the window that we query, as CloudWatch will
+/// never hand those events back to us again. This keeps memory flat over long watch sessions.
+#[derive(Default)]
+struct SeenEvents {
+    events: HashMap<String, i64>,
+    forgotten: usize,
+}
+
+/// Statistics about the event IDs held for deduplication.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SeenEventsStats {
+    /// How many event IDs are held now.
+    pub held: usize,
+    /// How many event IDs have been forgotten so far.
+    pub forgotten: usize,
+}
+
+impl SeenEvents {
+    /// Record the event, returning `true` if we have not seen it before.
+    fn insert(&mut self, event: &LogEvent) -> bool {
+        if self.events.contains_key(&event.event_id) {
+            return false;
+        }
+
+        self.events
+            .insert(event.event_id.clone(), unix_millis(event.timestamp));
+        true
+    }
+
+    /// Forget any events with a timestamp (in milliseconds) before the cutoff.
+    fn forget_before(&mut self, cutoff: i64) {
+        let before = self.events.len();
+        self.events.retain(|_, timestamp| *timestamp >= cutoff);
+        self.forgotten += before - self.events.len();
+    }
+
+    fn stats(&self) -> SeenEventsStats {
+        SeenEventsStats {
+            held: self.events.len(),
+            forgotten: self.forgotten,
+        }
+    }
+}
+
+/// How far before a group's high-water mark each poll starts by default, to catch events that were
+/// ingested late.
+pub const DEFAULT_POLL_OVERLAP: Duration = Duration::from_secs(30);
+
+/// A change in how watching a group is going, which the user should be told about.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GroupStatus {
+    /// Fetching events from the group failed. The same error isn't reported twice in a row.
+    Failed { group: String, message: String },
+    /// The group wasn't found too many times in a row, so we've given up on it.
+    Abandoned { group: String, attempts: usize },
+    /// Fetching events from the group succeeded after failing.
+    Recovered { group: String },
+}
+
+impl Display for GroupStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GroupStatus::Failed { group, message } => {
+                write!(f, "Error watching log group {}: {}", group, message)
+            }
+            GroupStatus::Abandoned { group, attempts } => write!(
+                f,
+                "Giving up on log group {}: not found after {} attempts",
+                group, attempts
+            ),
+            GroupStatus::Recovered { group } => write!(f, "Log group {} recovered", group),
+        }
+    }
+}
+
+/// What we know about each group between polls.
+struct GroupState {
+    group: WatchGroup,
+    /// The earliest time (in milliseconds) for which we want to see events.
+    earliest: i64,
+    /// The latest event timestamp (in milliseconds) that we've seen from this group, or the start
+    /// time until we've seen one. Events that are ingested late can still turn up from before it.
+    high_water_mark: i64,
+    /// Where to resume if the last poll ran out of budget for this group.
+    continuation: Option<Continuation>,
+    /// The last error we reported for this group, so that we don't repeat it every poll.
+    last_error: Option<String>,
+    /// How many polls in a row have found that this group does not exist.
+    not_found: usize,
+    /// Set while we are backing off from this group after being throttled.
+    backoff: Option<Backoff>,
+    /// Set once we have given up on this group.
+    abandoned: bool,
+    /// How many polls of this group have failed.
+    errors: usize,
+}
+
+/// Tracks the exponential backoff for a throttled group.
+struct Backoff {
+    delay: Duration,
+    retry_at: Instant,
+}
+
+/// The longest we will back off from a throttled group.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+impl GroupState {
+    /// Whether this group should be included in the next poll.
+    fn is_due(&self, now: Instant) -> bool {
+        !self.abandoned
+            && self
+                .backoff
+                .as_ref()
+                .is_none_or(|backoff| backoff.retry_at <= now)
+    }
+
+    /// Record a failure, and report the error unless it's the same as the last one we reported.
+    fn report_error(&mut self, message: String, report: &mut dyn FnMut(GroupStatus)) {
+        self.errors += 1;
+        if self.last_error.as_ref() != Some(&message) {
+            report(GroupStatus::Failed {
+                group: self.group.to_string(),
+                message: message.clone(),
+            });
+            self.last_error = Some(message);
+        }
+    }
+
+    /// Update the group after a failed poll.
+    fn poll_failed(
+        &mut self,
+        err: SourceError,
+        refresh: Duration,
+        max_not_found: usize,
+        report: &mut dyn FnMut(GroupStatus),
+    ) {
+        let SourceError { kind, message } = err;
+        self.report_error(message, report);
+
+        match kind {
+            SourceErrorKind::NotFound => {
+                self.not_found += 1;
+                if self.not_found >= max_not_found {
+                    report(GroupStatus::Abandoned {
+                        group: self.group.to_string(),
+                        attempts: self.not_found,
+                    });
+                    self.abandoned = true;
+                }
+            }
+            SourceErrorKind::Throttled => {
+                // Keep any continuation so we can resume once the backoff expires. We already wait
+                // `refresh` between polls, so backing off starts from twice that.
+                let delay = self
+                    .backoff
+                    .as_ref()
+                    .map_or(refresh * 2, |backoff| backoff.delay * 2)
+                    .min(MAX_BACKOFF);
+                self.backoff = Some(Backoff {
+                    delay,
+                    retry_at: Instant::now() + delay,
+                });
+            }
+            SourceErrorKind::Other => {
+                self.continuation = None;
+            }
+        }
+    }
+
+    /// Update the group after a successful poll.
+    fn poll_succeeded(&mut self, report: &mut dyn FnMut(GroupStatus)) {
+        if self.last_error.take().is_some() {
+            report(GroupStatus::Recovered {
+                group: self.group.to_string(),
+            });
+        }
+
+        self.not_found = 0;
+        self.backoff = None;
+    }
+
+    /// The time (in milliseconds) from which the next poll of this group should start.
+    fn start_time(&self, overlap: Duration) -> i64 {
+        match &self.continuation {
+            Some(continuation) => continuation.start_time,
+            None => (self.high_water_mark - overlap.as_millis() as i64).max(self.earliest),
+        }
+    }
+}
+
+/// Settings that control how we poll log groups.
+#[derive(Debug, Clone, Copy)]
+pub struct WatcherSettings {
+    /// How long we wait between polls. We wait twice this before polling a group again after it was
+    /// throttled, doubling each time it's throttled again.
+    pub refresh: Duration,
+    pub budget: PollBudget,
+    /// The earliest time for which we want to see events.
+    pub start_time: OffsetDateTime,
+    /// How many times in a row a group can be not found before we give up on it.
+    pub max_not_found: usize,
+    /// How far before each group's latest event each poll starts, to catch events that are ingested
+    /// late.
+    pub overlap: Duration,
+}
+
+/// What a [`Watcher`] has done so far.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WatchStats {
+    /// How many requests have been made to the sources.
+    pub requests: usize,
+    /// How many requests failed for each group, in the order the groups were given. Groups are
+    /// labelled as their events are.
+    pub errors: Vec<(String, usize)>,
+    /// The event IDs held to avoid showing an event twice.
+    pub seen_events: SeenEventsStats,
+}
+
+/// Polls a set of log groups for new events.
+pub struct Watcher<S> {
+    sources: HashMap<ClientContext, CountingLogSource<S>>,
+    groups: Vec<GroupState>,
+    seen_events: SeenEvents,
+    settings: WatcherSettings,
+    report: Box<dyn FnMut(GroupStatus) + Send>,
+    show_region: bool,
+}
+
+impl<S: LogSource> Watcher<S> {
+    /// Create a watcher for the groups, which must each have a source for their context. Groups
+    /// given more than once are only watched once.
+    pub fn new(
+        sources: HashMap<ClientContext, S>,
+        groups: Vec<WatchGroup>,
+        settings: WatcherSettings,
+    ) -> Self {
+        let start_time = unix_millis(settings.start_time);
+        let groups = dedup_groups(groups);
+        let show_region = show_region(&groups);
+        let groups = groups
+            .into_iter()
+            .map(|group| GroupState {
+                group,
+                earliest: start_time,
+                high_water_mark: start_time,
+                continuation: None,
+                last_error: None,
+                not_found: 0,
+                backoff: None,
+                abandoned: false,
+                errors: 0,
+            })
+            .collect();
+
+        Self {
+            sources: sources
+                .into_iter()
+                .map(|(context, source)| (context, CountingLogSource::new(source)))
+                .collect(),
+            groups,
+            seen_events: SeenEvents::default(),
+            settings,
+            report: Box::new(|_| {}),
+            show_region,
+        }
+    }
+
+    /// Whether the groups are in more than one region, so labels need to include it.
+    pub fn show_region(&self) -> bool {
+        self.show_region
+    }
+
+    /// Pass each change in how watching a group is going to `report`, such as a group failing,
+    /// recovering or being given up on. Without this, they aren't reported anywhere.
+    pub fn on_status(&mut self, report: impl FnMut(GroupStatus) + Send + 'static) {
+        self.report = Box::new(report);
+    }
+
+    /// Whether we have given up on every group.
+    pub fn is_finished(&self) -> bool {
+        self.groups.iter().all(|state| state.abandoned)
+    }
+
+    /// Turn the watcher into a stream of events, polling every `refresh` until we have given up on
+    /// every group.
+    pub fn into_stream(self) -> impl Stream<Item = LogEvent> {
+        events(self)
+    }
+
+    /// Stream events as [`Watcher::into_stream`] does, but keep hold of the watcher so that its
+    /// stats can be read once the stream is dropped.
+    pub fn stream(&mut self) -> impl Stream<Item = LogEvent> + '_ {
+        events(self)
+    }
+
+    /// The requests made and errors seen so far.
+    pub fn stats(&self) -> WatchStats {
+        WatchStats {
+            requests: self.sources.values().map(CountingLogSource::requests).sum(),
+            errors: self
+                .groups
+                .iter()
+                .map(|state| (state.group.label(self.show_region), state.errors))
+                .collect(),
+            seen_events: self.seen_events.stats(),
+        }
+    }
+
+    /// Fetch the `count` most recent events from before the start time that pass the filter, across
+    /// all of the groups and in timestamp order. These won't be returned again by polling.
+    pub async fn backlog(
+        &mut self,
+        count: usize,
+        filter: impl Fn(&LogEvent) -> bool,
+    ) -> Vec<LogEvent> {
+        let groups: Vec<WatchGroup> = self
+            .groups
+            .iter()
+            .map(|state| state.group.clone())
+            .collect();
+        let (events, errors) = recent_events(
+            &self.sources,
+            &groups,
+            count,
+            unix_millis(self.settings.start_time),
+            filter,
+        )
+        .await;
+
+        // Polling will deal with the groups that failed
+        for (group, err) in errors {
+            if let Some(state) = self
+                .groups
+                .iter_mut()
+                .find(|state| state.group.is_same_group(group))
+            {
+                state.report_error(err.message, &mut self.report);
+            }
+        }
+
+        for event in &events {
+            self.seen_events.insert(event);
+        }
+
+        events
+    }
+
+    /// Poll each group that is due, returning the new events in timestamp order.
+    pub async fn poll(&mut self) -> Vec<LogEvent> {
+        let WatcherSettings {
+            refresh,
+            budget,
+            max_not_found,
+            overlap,
+            ..
+        } = self.settings;
+
+        let now = Instant::now();
+        let queries = FuturesUnordered::new();
+        for state in self.groups.iter_mut().filter(|state| state.is_due(now)) {
+            let start_time = state.start_time(overlap);
+            let next_token = state
+                .continuation
+                .as_ref()
+                .map(|continuation| continuation.next_token.clone());
+
+            let source = &self.sources[&state.group.context];
+            queries.push(async move {
+                let result =
+                    get_group_events(source, &state.group, start_time, None, next_token, budget)
+                        .await;
+                (state, result)
+            });
+        }
+
+        let results = queries.collect::<Vec<_>>().await;
+        let mut new_events = Vec::new();
+
+        for (state, result) in results {
+            let GroupEvents {
+                events,
+                continuation,
+                ..
+            } = match result {
+                Ok(group_events) => group_events,
+                Err(err) => {
+                    state.poll_failed(err, refresh, max_not_found, &mut self.report);
+                    continue;
+                }
+            };
+
+            state.poll_succeeded(&mut self.report);
+            state.continuation = continuation;
+
+            for event in events {
+                state.high_water_mark = state.high_water_mark.max(unix_millis(event.timestamp));
+                if self.seen_events.insert(&event) {
+                    new_events.push(event);
+                }
+            }
+        }
+
+        // Nothing older than the earliest window we're still querying can be returned again
+        if let Some(cutoff) = self
+            .groups
+            .iter()
+            .filter(|state| !state.abandoned)
+            .map(|state| state.start_time(overlap))
+            .min()
+        {
+            self.seen_events.forget_before(cutoff);
+        }
+
+        let stats = self.seen_events.stats();
+        log::debug!(
+            "Holding {} event IDs for deduplication ({} forgotten so far)",
+            stats.held,
+            stats.forgotten
+        );
+
+        new_events.sort_by_key(|event| event.timestamp);
+        new_events
+    }
+}
+
+/// Stream a watcher's events, polling every `refresh` until we have given up on every group.
+fn events<S: LogSource, W: BorrowMut<Watcher<S>>>(watcher: W) -> impl Stream<Item = LogEvent> {
+    stream::unfold((watcher, false), |(mut watcher, wait)| async move {
+        let events = {
+            let watcher = watcher.borrow_mut();
+            if watcher.is_finished() {
+                return None;
+            }
+
+            if wait {
+                tokio::time::sleep(watcher.settings.refresh).await;
+            }
+
+            watcher.poll().await
+        };
+        Some((stream::iter(events), (watcher, true)))
+    })
+    .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{
+        memory::MemoryLogSource,
+        test_support::{context, group, messages, seconds_ago, sources},
+    };
+
+    fn settings() -> WatcherSettings {
+        WatcherSettings {
+            refresh: Duration::from_secs(60),
+            budget: PollBudget::default(),
+            start_time: OffsetDateTime::now_utc() - Duration::from_secs(600),
+            max_not_found: 3,
+            overlap: DEFAULT_POLL_OVERLAP,
+        }
+    }
+
+    fn watcher(
+        source: MemoryLogSource,
+        groups: &[&str],
+        settings: WatcherSettings,
+    ) -> Watcher<MemoryLogSource> {
+        Watcher::new(
+            sources(source),
+            groups.iter().map(|name| group(name)).collect(),
+            settings,
+        )
+    }
+
+    #[test]
+    fn parses_plain_group_spec() {
+        let spec = GroupSpec::parse("/ecs/service");
+        assert_eq!(spec.qualifier, None);
+        assert_eq!(spec.region, None);
+        assert_eq!(spec.name, "/ecs/service");
+    }
+
+    #[test]
+    fn parses_qualified_group_spec() {
+        let spec = GroupSpec::parse("prod@us-east-1:/ecs/service");
+        assert_eq!(spec.qualifier, Some("prod"));
+        assert_eq!(spec.region, Some("us-east-1"));
+        assert_eq!(spec.name, "/ecs/service");
+    }
+
+    #[test]
+    fn parses_role_arn_group_spec() {
+        let spec = GroupSpec::parse("arn:aws:iam::123456789012:role/reader@/ecs/service");
+        assert_eq!(
+            spec.qualifier,
+            Some("arn:aws:iam::123456789012:role/reader")
+        );
+        assert_eq!(spec.region, None);
+        assert_eq!(spec.name, "/ecs/service");
+    }
+
+    #[test]
+    fn resolves_groups_with_their_filters() {
+        let specs = [
+            "a".to_string(),
+            "prod@us-east-1:b".to_string(),
+            "arn:aws:iam::123456789012:role/reader@c".to_string(),
+        ];
+        let filters = GroupFilters {
+            filter: Some("ERROR".to_string()),
+            group_filters: parse_group_filters(&[
+                "b=WARN".to_string(),
+                "arn:aws:iam::123456789012:role/reader@c=FATAL".to_string(),
+            ])
+            .unwrap(),
+            stream_prefix: Some("web".to_string()),
+            stream_names: Vec::new(),
+        };
+
+        let mut default_context = context();
+        default_context.profile = Some("dev".to_string());
+        let groups = resolve_groups(&specs, &default_context, &filters);
+
+        assert_eq!(groups[0].context, default_context);
+        assert_eq!(groups[0].filter_pattern.as_deref(), Some("ERROR"));
+
+        assert_eq!(groups[1].context.profile.as_deref(), Some("prod"));
+        assert_eq!(groups[1].context.region, "us-east-1");
+        assert_eq!(groups[1].name, "b");
+        assert_eq!(groups[1].filter_pattern.as_deref(), Some("WARN"));
+
+        assert_eq!(groups[2].context.profile.as_deref(), Some("dev"));
+        assert_eq!(
+            groups[2].context.role_arn.as_deref(),
+            Some("arn:aws:iam::123456789012:role/reader")
+        );
+        assert_eq!(groups[2].filter_pattern.as_deref(), Some("FATAL"));
+        assert!(groups
+            .iter()
+            .all(|group| group.stream_prefix.as_deref() == Some("web")));
+
+        assert!(show_region(&groups));
+        assert!(!show_region(&groups[..1]));
+    }
+
+    #[test]
+    fn resolves_each_group_once() {
+        let specs = ["a".to_string(), "eu-west-1:a".to_string(), "b".to_string()];
+        let groups = resolve_groups(&specs, &context(), &GroupFilters::default());
+        assert_eq!(
+            groups.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            vec!["eu-west-1:a", "eu-west-1:b"]
+        );
+    }
+
+    #[test]
+    fn rejects_group_filters_without_a_pattern() {
+        assert_eq!(
+            parse_group_filters(&["a".to_string()]).unwrap_err(),
+            "Expected GROUP=PATTERN for --group-filter: a"
+        );
+    }
+
+    #[tokio::test]
+    async fn dedups_events_across_polls() {
+        let source = MemoryLogSource::new();
+        source.push_event("a", "s", seconds_ago(10), "first");
+        let mut watcher = watcher(source, &["a"], settings());
+
+        assert_eq!(messages(&watcher.poll().await), vec!["first"]);
+
+        // The overlap means the next poll sees the first event again, but we only report it once
+        assert!(watcher.poll().await.is_empty());
+
+        watcher.sources[&context()]
+            .inner()
+            .push_event("a", "s", seconds_ago(5), "second");
+        assert_eq!(messages(&watcher.poll().await), vec!["second"]);
+    }
+
+    #[tokio::test]
+    async fn orders_events_across_groups() {
+        let source = MemoryLogSource::new();
+        source.push_event("a", "s", seconds_ago(30), "one");
+        source.push_event("b", "s", seconds_ago(20), "two");
+        source.push_event("a", "s", seconds_ago(10), "three");
+        let mut watcher = watcher(source, &["a", "b"], settings());
+
+        assert_eq!(messages(&watcher.poll().await), vec!["one", "two", "three"]);
+    }
+
+    #[tokio::test]
+    async fn ignores_events_before_the_start_time() {
+        let source = MemoryLogSource::new();
+        source.push_event("a", "s", seconds_ago(700), "too old");
+        source.push_event("a", "s", seconds_ago(10), "recent");
+        let mut watcher = watcher(source, &["a"], settings());
+
+        assert_eq!(messages(&watcher.poll().await), vec!["recent"]);
+    }
+
+    #[tokio::test]
+    async fn shows_backlog_before_following() {
+        let start_time = OffsetDateTime::now_utc() - Duration::from_secs(15);
+        let source = MemoryLogSource::new();
+        source.push_event("a", "s", seconds_ago(3600), "old");
+        source.push_event("a", "s", seconds_ago(20), "before");
+        source.push_event("b", "s", unix_millis(start_time), "at start");
+        source.push_event("b", "s", seconds_ago(5), "new");
+        let mut watcher = watcher(
+            source,
+            &["a", "b"],
+            WatcherSettings {
+                start_time,
+                ..settings()
+            },
+        );
+
+        assert_eq!(
+            messages(&watcher.backlog(2, |_| true).await),
+            vec!["before", "at start"]
+        );
+        assert_eq!(messages(&watcher.poll().await), vec!["new"]);
+    }
+
+    #[tokio::test]
+    async fn pages_through_every_event() {
+        let source = MemoryLogSource::with_page_size(2);
+        for index in 0..5 {
+            source.push_event("a", "s", seconds_ago(50 - index), &index.to_string());
+        }
+        let mut watcher = watcher(source, &["a"], settings());
+
+        assert_eq!(
+            messages(&watcher.poll().await),
+            vec!["0", "1", "2", "3", "4"]
+        );
+        assert_eq!(watcher.sources[&context()].inner().calls(), 3);
+    }
+
+    #[tokio::test]
+    async fn resumes_when_out_of_budget() {
+        let source = MemoryLogSource::with_page_size(2);
+        for index in 0..5 {
+            source.push_event("a", "s", seconds_ago(50 - index), &index.to_string());
+        }
+        source.push_event("b", "s", seconds_ago(1), "other");
+
+        let mut settings = settings();
+        settings.budget.max_pages = 1;
+        let mut watcher = watcher(source, &["a", "b"], settings);
+
+        // The noisy group only gets one page per poll, so the other group still gets a look in
+        assert_eq!(messages(&watcher.poll().await), vec!["0", "1", "other"]);
+        assert_eq!(messages(&watcher.poll().await), vec!["2", "3"]);
+        assert_eq!(messages(&watcher.poll().await), vec!["4"]);
+        assert!(watcher.poll().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_missing_groups() {
+        let source = MemoryLogSource::new();
+        source.push_event("a", "s", seconds_ago(10), "hello");
+        let mut watcher = watcher(source, &["a", "missing"], settings());
+
+        for _ in 0..3 {
+            watcher.poll().await;
+        }
+        assert!(watcher.groups[1].abandoned);
+        assert!(!watcher.is_finished());
+
+        // We no longer ask for the missing group
+        let calls = watcher.sources[&context()].inner().calls();
+        watcher.poll().await;
+        assert_eq!(watcher.sources[&context()].inner().calls(), calls + 1);
+    }
+
+    #[tokio::test]
+    async fn counts_requests_and_errors() {
+        let source = MemoryLogSource::new();
+        source.push_event("a", "s", seconds_ago(10), "hello");
+        let mut watcher = watcher(source, &["a", "missing"], settings());
+
+        // Stop streaming part way through, as we would on Ctrl+C
+        let mut events = Box::pin(watcher.stream());
+        assert_eq!(events.next().await.unwrap().message, "hello");
+        drop(events);
+
+        assert_eq!(
+            watcher.stats(),
+            WatchStats {
+                requests: 2,
+                errors: vec![("a".to_string(), 0), ("missing".to_string(), 1)],
+                seen_events: SeenEventsStats {
+                    held: 1,
+                    forgotten: 0
+                },
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn watches_each_group_once() {
+        let source = MemoryLogSource::new();
+        source.push_event("a", "s", seconds_ago(10), "hello");
+        let mut watcher = watcher(source, &["a", "a"], settings());
+
+        assert_eq!(messages(&watcher.poll().await), vec!["hello"]);
+        assert_eq!(watcher.stats().requests, 1);
+        assert_eq!(watcher.stats().errors, vec![("a".to_string(), 0)]);
+    }
+
+    #[test]
+    fn labels_groups_with_their_region_when_there_is_more_than_one() {
+        let other = ClientContext {
+            region: "us-east-1".to_string(),
+            ..context()
+        };
+        let mut both_regions = sources(MemoryLogSource::new());
+        both_regions.insert(other.clone(), MemoryLogSource::new());
+        let groups = vec![
+            group("a"),
+            WatchGroup {
+                context: other,
+                ..group("b")
+            },
+        ];
+
+        let watcher = Watcher::new(both_regions, groups.clone(), settings());
+        assert!(watcher.show_region());
+        assert_eq!(
+            watcher.stats().errors,
+            vec![
+                ("eu-west-1:a".to_string(), 0),
+                ("us-east-1:b".to_string(), 0)
+            ]
+        );
+
+        let watcher = Watcher::new(
+            sources(MemoryLogSource::new()),
+            groups[..1].to_vec(),
+            settings(),
+        );
+        assert!(!watcher.show_region());
+        assert_eq!(watcher.stats().errors, vec![("a".to_string(), 0)]);
+    }
+
+    #[tokio::test]
+    async fn finishes_when_every_group_is_missing() {
+        let mut settings = settings();
+        settings.max_not_found = 1;
+        let mut watcher = watcher(MemoryLogSource::new(), &["missing"], settings);
+
+        watcher.poll().await;
+        assert!(watcher.is_finished());
+    }
+
+    #[tokio::test]
+    async fn backs_off_when_throttled() {
+        let source = MemoryLogSource::new();
+        source.push_event("a", "s", seconds_ago(10), "from a");
+        source.push_event("b", "s", seconds_ago(5), "from b");
+        source.fail_next("a", SourceErrorKind::Throttled);
+        let mut settings = settings();
+        settings.refresh = Duration::from_millis(50);
+        let mut watcher = watcher(source, &["a", "b"], settings);
+
+        // Wait between polls as the stream does
+        assert_eq!(messages(&watcher.poll().await), vec!["from b"]);
+        tokio::time::sleep(settings.refresh).await;
+
+        // The throttled group sits out the next poll
+        let calls = watcher.sources[&context()].inner().calls();
+        assert!(watcher.poll().await.is_empty());
+        assert_eq!(watcher.sources[&context()].inner().calls(), calls + 1);
+        tokio::time::sleep(settings.refresh).await;
+
+        assert_eq!(messages(&watcher.poll().await), vec!["from a"]);
+        assert!(watcher.groups[0].backoff.is_none());
+    }
+
+    #[tokio::test]
+    async fn recovers_from_other_errors() {
+        let source = MemoryLogSource::new();
+        source.push_event("a", "s", seconds_ago(10), "hello");
+        source.fail_next("a", SourceErrorKind::Other);
+        let mut watcher = watcher(source, &["a"], settings());
+
+        assert!(watcher.poll().await.is_empty());
+        assert!(watcher.groups[0].last_error.is_some());
+
+        assert_eq!(messages(&watcher.poll().await), vec!["hello"]);
+        assert!(watcher.groups[0].last_error.is_none());
+    }
+
+    #[tokio::test]
+    async fn reports_status_changes() {
+        let source = MemoryLogSource::new();
+        source.push_event("a", "s", seconds_ago(10), "hello");
+        source.fail_next("a", SourceErrorKind::Other);
+        let mut settings = settings();
+        settings.max_not_found = 2;
+        let mut watcher = watcher(source, &["a", "missing"], settings);
+
+        let statuses = Arc::new(Mutex::new(Vec::new()));
+        let reported = statuses.clone();
+        watcher.on_status(move |status| reported.lock().unwrap().push(status));
+
+        watcher.poll().await;
+        watcher.poll().await;
+
+        // The missing group's error is only reported the first time
+        assert_eq!(
+            *statuses.lock().unwrap(),
+            vec![
+                GroupStatus::Failed {
+                    group: "eu-west-1:a".to_string(),
+                    message: "Other error for a".to_string()
+                },
+                GroupStatus::Failed {
+                    group: "eu-west-1:missing".to_string(),
+                    message: "The specified log group does not exist.".to_string()
+                },
+                GroupStatus::Recovered {
+                    group: "eu-west-1:a".to_string()
+                },
+                GroupStatus::Abandoned {
+                    group: "eu-west-1:missing".to_string(),
+                    attempts: 2
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_events_across_polls() {
+        let source = MemoryLogSource::new();
+        source.push_event("a", "s", seconds_ago(10), "first");
+        let mut settings = settings();
+        settings.refresh = Duration::from_millis(1);
+        let mut events = Box::pin(watcher(source.clone(), &["a"], settings).into_stream());
+        assert_eq!(events.next().await.unwrap().message, "first");
+
+        source.push_event("a", "s", seconds_ago(5), "second");
+        assert_eq!(events.next().await.unwrap().message, "second");
+    }
+
+    #[tokio::test]
+    async fn stream_ends_when_every_group_is_missing() {
+        let mut settings = settings();
+        settings.max_not_found = 1;
+        let watcher = watcher(MemoryLogSource::new(), &["missing"], settings);
+
+        assert!(watcher.into_stream().collect::<Vec<_>>().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn catches_events_ingested_late() {
+        let source = MemoryLogSource::new();
+        source.push_event("a", "s", seconds_ago(100), "first");
+        let mut watcher = watcher(source, &["a"], settings());
+        assert_eq!(messages(&watcher.poll().await), vec!["first"]);
+
+        // The group was drained, but an event from before the last poll turns up later
+        watcher.sources[&context()]
+            .inner()
+            .push_event("a", "s", seconds_ago(90), "late");
+        assert_eq!(messages(&watcher.poll().await), vec!["late"]);
+    }
+
+    #[tokio::test]
+    async fn forgets_events_outside_the_window() {
+        let source = MemoryLogSource::new();
+        source.push_event("a", "s", seconds_ago(300), "old");
+        source.push_event("a", "s", seconds_ago(5), "new");
+        let mut watcher = watcher(source, &["a"], settings());
+
+        watcher.poll().await;
+
+        // We only look back over the overlap from the newest event, so the old event goes
+        assert_eq!(
+            watcher.stats().seen_events,
+            SeenEventsStats {
+                held: 1,
+                forgotten: 1
+            }
+        );
+    }
+}